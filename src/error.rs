@@ -1,5 +1,9 @@
+use git2::{ErrorClass, ErrorCode};
+use serde::Serialize;
 use thiserror::Error as ThisError;
 
+use crate::repo::SnapshotOutcome;
+
 #[derive(Debug, ThisError)]
 pub enum Error {
     #[error("git error: {0:?}")]
@@ -12,4 +16,41 @@ pub enum Error {
     Json(#[from] serde_json::error::Error),
     #[error("notify error: {0:?}")]
     Notify(#[from] notify::Error),
+    /// The snapshot was saved but pushing it failed by the push failure policy
+    #[error("push error: {:?}", .0.push_failures())]
+    Push(Box<SnapshotOutcome>),
+    #[error("signing error: {0}")]
+    Sign(String),
+    #[error("snapshot not found")]
+    SnapshotNotFound,
+    #[error("unsaved changes, unable to take a safety snapshot")]
+    UnsavedChanges,
+}
+
+/// Why pushing snapshots to a remote failed
+#[derive(Debug, Clone, PartialEq, Eq, ThisError, Serialize)]
+#[serde(tag = "kind", content = "message", rename_all = "kebab-case")]
+pub enum PushError {
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("network error: {0}")]
+    Network(String),
+    #[error("rejected: {0}")]
+    Rejected(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<git2::Error> for PushError {
+    fn from(err: git2::Error) -> Self {
+        let message = err.message().to_owned();
+        match (err.code(), err.class()) {
+            (ErrorCode::Auth, _) => Self::Auth(message),
+            (ErrorCode::NotFastForward, _) => Self::Rejected(message),
+            (_, ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh | ErrorClass::Ssl) => {
+                Self::Network(message)
+            }
+            _ => Self::Other(message),
+        }
+    }
 }
@@ -0,0 +1,190 @@
+use std::path::Path;
+
+use git2::{Config, Pathspec, PathspecFlags};
+
+use crate::error::Error;
+use crate::util::ConfigValue;
+
+/// Pathspecs forcing paths into or out of snapshots independent of .gitignore.
+/// Excludes take precedence over includes, which take precedence over .gitignore.
+/// Files larger than a maximum size are left out too.
+pub struct PathFilter {
+    include: Option<Pathspec>,
+    exclude: Option<Pathspec>,
+    max_file_size: u64,
+    specs: String,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, Error> {
+        let pathspec = |specs: &[String]| -> Result<Option<Pathspec>, Error> {
+            if specs.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(Pathspec::new(specs)?))
+        };
+        Ok(Self {
+            include: pathspec(include)?,
+            exclude: pathspec(exclude)?,
+            max_file_size: 0,
+            specs: format!("{:?} {:?}", include, exclude),
+        })
+    }
+
+    /// Sets the maximum file size in bytes, 0 disables the limit
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn from_config(config: &Config, current_branch: &str) -> Result<Self, Error> {
+        let include = Vec::<String>::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotinclude", current_branch),
+                "snapshot.include",
+            ],
+            Vec::new(),
+        );
+        let exclude = Vec::<String>::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotexclude", current_branch),
+                "snapshot.exclude",
+            ],
+            Vec::new(),
+        );
+        let max_file_size = i64::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotmaxfilesize", current_branch),
+                "snapshot.maxfilesize",
+            ],
+            0,
+        );
+        Ok(Self::new(&include, &exclude)?.with_max_file_size(max_file_size.max(0) as u64))
+    }
+
+    /// Describes the filter, equal for filters that leave out the same paths
+    pub fn key(&self) -> String {
+        format!("{} {}", self.specs, self.max_file_size)
+    }
+
+    /// Whether ignored paths need to be visited to find included ones
+    pub fn has_includes(&self) -> bool {
+        self.include.is_some()
+    }
+
+    /// Whether file sizes need to be checked
+    pub fn has_max_file_size(&self) -> bool {
+        self.max_file_size > 0
+    }
+
+    pub fn is_oversized(&self, size: u64) -> bool {
+        self.has_max_file_size() && size > self.max_file_size
+    }
+
+    /// Decides whether a worktree path goes into a snapshot given its .gitignore status
+    pub fn is_included(&self, path: &Path, ignored: bool) -> bool {
+        let matches = |spec: &Option<Pathspec>| {
+            spec.as_ref()
+                .is_some_and(|spec| spec.matches_path(path, PathspecFlags::DEFAULT))
+        };
+        if matches(&self.exclude) {
+            return false;
+        }
+        matches(&self.include) || !ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::test_repo;
+    use tempfile::tempdir;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let owned = |specs: &[&str]| specs.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
+        PathFilter::new(&owned(include), &owned(exclude)).unwrap()
+    }
+
+    #[test]
+    fn no_patterns() {
+        let filter = filter(&[], &[]);
+        assert!(!filter.has_includes());
+        assert!(filter.is_included(Path::new("file"), false));
+        assert!(!filter.is_included(Path::new("file"), true));
+    }
+
+    #[test]
+    fn include_ignored() {
+        let filter = filter(&[".env.local", "notes/*"], &[]);
+        assert!(filter.has_includes());
+        assert!(filter.is_included(Path::new(".env.local"), true));
+        assert!(filter.is_included(Path::new("notes/todo.md"), true));
+        assert!(!filter.is_included(Path::new("target/debug"), true));
+        assert!(filter.is_included(Path::new("src/lib.rs"), false));
+    }
+
+    #[test]
+    fn exclude_tracked() {
+        let filter = filter(&[], &["*.bin"]);
+        assert!(!filter.is_included(Path::new("assets/model.bin"), false));
+        assert!(filter.is_included(Path::new("src/lib.rs"), false));
+    }
+
+    #[test]
+    fn exclude_over_include() {
+        let filter = filter(&["notes/*"], &["notes/private/*"]);
+        assert!(filter.is_included(Path::new("notes/todo.md"), true));
+        assert!(!filter.is_included(Path::new("notes/private/key"), true));
+        assert!(!filter.is_included(Path::new("notes/private/key"), false));
+    }
+
+    #[test]
+    fn max_file_size() {
+        let filter = filter(&[], &[]);
+        assert!(!filter.has_max_file_size());
+        assert!(!filter.is_oversized(u64::MAX));
+
+        let filter = filter.with_max_file_size(10);
+        assert!(filter.has_max_file_size());
+        assert!(!filter.is_oversized(10));
+        assert!(filter.is_oversized(11));
+    }
+
+    #[test]
+    fn max_file_size_from_config() {
+        let temp = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp.path());
+        config.set_str("snapshot.maxfilesize", "1k").unwrap();
+
+        let filter = PathFilter::from_config(&config, "main").unwrap();
+        assert!(!filter.is_oversized(1024));
+        assert!(filter.is_oversized(1025));
+    }
+
+    #[test]
+    fn from_config_branch_precedence() {
+        let temp = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp.path());
+        config
+            .set_multivar("snapshot.exclude", "^$", "*.bin")
+            .unwrap();
+        config
+            .set_multivar("snapshot.exclude", "^$", "*.iso")
+            .unwrap();
+        config
+            .set_multivar("branch.main.snapshotexclude", "^$", "*.log")
+            .unwrap();
+
+        let filter = PathFilter::from_config(&config, "main").unwrap();
+        assert!(!filter.is_included(Path::new("debug.log"), false));
+        assert!(filter.is_included(Path::new("model.bin"), false));
+
+        let filter = PathFilter::from_config(&config, "other").unwrap();
+        assert!(!filter.is_included(Path::new("model.bin"), false));
+        assert!(!filter.is_included(Path::new("disk.iso"), false));
+        assert!(filter.is_included(Path::new("debug.log"), false));
+    }
+}
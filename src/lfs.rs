@@ -0,0 +1,259 @@
+use std::fs::{create_dir_all, rename, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use git2::{AttrCheckFlags, Repository};
+use sha2::{Digest, Sha256};
+
+use crate::util::common_dir;
+
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+// pointer files are always smaller than this, larger files are never parsed
+const MAX_POINTER_SIZE: u64 = 1024;
+
+/// Git LFS pointer to an object in the LFS object store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LfsPointer {
+    pub oid: String,
+    pub size: u64,
+}
+
+impl LfsPointer {
+    pub fn parse(content: &[u8]) -> Option<Self> {
+        let content = std::str::from_utf8(content).ok()?;
+        let mut lines = content.lines();
+        if lines.next()? != POINTER_VERSION {
+            return None;
+        }
+        let mut oid = None;
+        let mut size = None;
+        for line in lines {
+            if let Some(value) = line.strip_prefix("oid sha256:") {
+                if value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    oid = Some(value.to_owned());
+                }
+            } else if let Some(value) = line.strip_prefix("size ") {
+                size = value.parse().ok();
+            }
+        }
+        Some(Self {
+            oid: oid?,
+            size: size?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "{}\noid sha256:{}\nsize {}\n",
+            POINTER_VERSION, self.oid, self.size
+        )
+        .into_bytes()
+    }
+}
+
+/// Local LFS storage of a repository, `<common git dir>/lfs`
+pub struct LfsStore {
+    dir: PathBuf,
+}
+
+impl LfsStore {
+    pub fn new(git_dir: &Path) -> Self {
+        // linked worktrees share the LFS objects of the main repository
+        Self {
+            dir: common_dir(git_dir).join("lfs"),
+        }
+    }
+
+    pub fn object_path(&self, oid: &str) -> PathBuf {
+        self.dir
+            .join("objects")
+            .join(&oid[..2])
+            .join(&oid[2..4])
+            .join(oid)
+    }
+
+    /// Copies a worktree file into the object store unless it's stored already, returning its
+    /// pointer. Files that are already pointers are returned as is.
+    pub fn store(&self, path: &Path) -> io::Result<LfsPointer> {
+        let pointer = self.pointer(path)?;
+        if self.object_path(&pointer.oid).exists() {
+            return Ok(pointer);
+        }
+
+        // Hash again while copying to a temporary file in case the file changed in between,
+        // then move it to its content addressed path
+        let tmp_dir = self.dir.join("tmp");
+        create_dir_all(&tmp_dir)?;
+        let tmp_path = tmp_dir.join(format!(
+            "{}-{}",
+            process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos())
+        ));
+
+        let mut dst = File::create(&tmp_path)?;
+        let pointer = hash_file(path, Some(&mut dst))?;
+        drop(dst);
+
+        let object_path = self.object_path(&pointer.oid);
+        if object_path.exists() {
+            std::fs::remove_file(&tmp_path)?;
+        } else {
+            create_dir_all(object_path.parent().unwrap_or(&self.dir))?;
+            rename(&tmp_path, &object_path)?;
+        }
+        Ok(pointer)
+    }
+
+    /// Returns the pointer of a worktree file without storing its content
+    pub fn pointer(&self, path: &Path) -> io::Result<LfsPointer> {
+        match read_pointer(path)? {
+            Some(pointer) => Ok(pointer),
+            None => hash_file(path, None),
+        }
+    }
+
+    /// Replaces a worktree pointer file with its content if the object is stored locally
+    pub fn smudge(&self, path: &Path) -> io::Result<bool> {
+        let pointer = match read_pointer(path)? {
+            Some(pointer) => pointer,
+            None => return Ok(false),
+        };
+        let object_path = self.object_path(&pointer.oid);
+        if !object_path.exists() {
+            return Ok(false);
+        }
+        // copy takes the permissions of the object, the file keeps its own, e.g. its exec bit
+        let permissions = std::fs::metadata(path)?.permissions();
+        std::fs::copy(object_path, path)?;
+        std::fs::set_permissions(path, permissions)?;
+        Ok(true)
+    }
+}
+
+/// Whether .gitattributes marks a path as tracked by Git LFS
+pub fn is_lfs_path(repo: &Repository, path: &Path) -> bool {
+    matches!(
+        repo.get_attr(path, "filter", AttrCheckFlags::default()),
+        Ok(Some("lfs"))
+    )
+}
+
+// hashes a file into its pointer, copying it to `dst` on the way
+fn hash_file(path: &Path, mut dst: Option<&mut File>) -> io::Result<LfsPointer> {
+    let mut src = File::open(path)?;
+    let mut sha = Sha256::new();
+    let mut size = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = src.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        sha.update(&buf[..n]);
+        if let Some(dst) = dst.as_mut() {
+            dst.write_all(&buf[..n])?;
+        }
+        size += n as u64;
+    }
+    Ok(LfsPointer {
+        oid: format!("{:x}", sha.finalize()),
+        size,
+    })
+}
+
+fn read_pointer(path: &Path) -> io::Result<Option<LfsPointer>> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() >= MAX_POINTER_SIZE {
+        return Ok(None);
+    }
+    let mut content = Vec::new();
+    file.take(MAX_POINTER_SIZE).read_to_end(&mut content)?;
+    Ok(LfsPointer::parse(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const HELLO_OID: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn pointer() {
+        let pointer = LfsPointer {
+            oid: HELLO_OID.to_owned(),
+            size: 5,
+        };
+        let bytes = pointer.to_bytes();
+        assert_eq!(
+            format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 5\n",
+                HELLO_OID
+            )
+            .into_bytes(),
+            bytes
+        );
+        assert_eq!(Some(pointer), LfsPointer::parse(&bytes));
+        assert_eq!(None, LfsPointer::parse(b"hello"));
+        assert_eq!(
+            None,
+            LfsPointer::parse(
+                b"version https://git-lfs.github.com/spec/v1\noid sha256:2c\nsize 5\n"
+            )
+        );
+    }
+
+    #[test]
+    fn store_and_smudge() {
+        let git_dir = tempdir().unwrap();
+        let worktree = tempdir().unwrap();
+        let store = LfsStore::new(git_dir.path());
+
+        let path = worktree.path().join("file");
+        std::fs::write(&path, "hello").unwrap();
+
+        let pointer = store.pointer(&path).unwrap();
+        assert!(!store.object_path(HELLO_OID).exists());
+        assert_eq!(pointer, store.store(&path).unwrap());
+        assert_eq!(HELLO_OID, pointer.oid);
+        assert_eq!(5, pointer.size);
+
+        let object_path = git_dir.path().join("lfs/objects/2c/f2").join(HELLO_OID);
+        assert_eq!(object_path, store.object_path(HELLO_OID));
+        assert_eq!("hello", std::fs::read_to_string(&object_path).unwrap());
+
+        // Stored objects aren't copied again
+        std::fs::write(&object_path, "stored").unwrap();
+        assert_eq!(pointer, store.store(&path).unwrap());
+        assert_eq!("stored", std::fs::read_to_string(&object_path).unwrap());
+        std::fs::write(&object_path, "hello").unwrap();
+
+        // A pointer file stores as itself and smudges back to the content
+        std::fs::write(&path, pointer.to_bytes()).unwrap();
+        assert_eq!(pointer, store.store(&path).unwrap());
+        assert!(store.smudge(&path).unwrap());
+        assert_eq!("hello", std::fs::read_to_string(&path).unwrap());
+        assert!(!store.smudge(&path).unwrap());
+    }
+
+    #[test]
+    fn linked_worktree_store() {
+        let git_dir = tempdir().unwrap();
+        let worktree_git_dir = git_dir.path().join("worktrees/linked");
+        create_dir_all(&worktree_git_dir).unwrap();
+        std::fs::write(worktree_git_dir.join("commondir"), "../..\n").unwrap();
+
+        let store = LfsStore::new(&worktree_git_dir);
+        assert_eq!(
+            worktree_git_dir
+                .join("../..")
+                .join("lfs/objects/2c/f2")
+                .join(HELLO_OID),
+            store.object_path(HELLO_OID)
+        );
+    }
+}
@@ -1,7 +1,15 @@
 mod error;
+mod filter;
+mod lfs;
 mod repo;
 pub mod repo_watcher;
+mod retention;
+mod shadow;
+mod sign;
 mod util;
 pub mod watcher;
 pub use error::*;
+pub use filter::*;
 pub use repo::*;
+pub use retention::*;
+pub use sign::{SignFormat, Signer};
@@ -1,6 +1,8 @@
 use git_snapshot::repo_watcher::{RepoWatcher, WatchConfig};
 
-use git_snapshot::Repo;
+use git2::{Config, Diff, DiffFormat, DiffStatsFormat, Time};
+use git_snapshot::{ChangeKind, DryRun, Repo, SkipReason, SnapshotOutcome};
+use humantime::{format_rfc3339_seconds, parse_duration};
 use log::{error, LevelFilter};
 use serde_json::{from_reader, to_writer};
 use structopt::StructOpt;
@@ -16,20 +18,17 @@ use std::str::FromStr;
 use pretty_env_logger::formatted_builder;
 use std::path::{Path, PathBuf};
 use std::thread::park;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 enum LogLevel {
     Off,
     Error,
     Warn,
+    #[default]
     Info,
     Debug,
 }
-impl Default for LogLevel {
-    fn default() -> Self {
-        Self::Info
-    }
-}
 
 impl Display for LogLevel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -82,6 +81,13 @@ struct App {
         about = "error,warn,info,debug"
     )]
     log_level: LogLevel,
+    #[structopt(
+        long,
+        about = "Show what a snapshot would contain and where it would be pushed, without taking it"
+    )]
+    dry_run: bool,
+    #[structopt(long, about = "Print the snapshot outcome as JSON")]
+    json: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -90,6 +96,11 @@ enum AppCommands {
     Watch {
         #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "Config path")]
         config: Option<PathBuf>,
+        #[structopt(
+            long,
+            about = "Create a shadow repository for a directory that isn't a git repo"
+        )]
+        init_shadow: bool,
         #[structopt(about = "Repo path")]
         path: PathBuf,
     },
@@ -100,6 +111,51 @@ enum AppCommands {
         #[structopt(about = "repo path")]
         path: PathBuf,
     },
+    #[structopt(about = "Restore a snapshot into the working tree")]
+    Restore {
+        #[structopt(
+            short,
+            long,
+            about = "Restore even if the current changes can't be saved in a safety snapshot"
+        )]
+        force: bool,
+        #[structopt(about = "Snapshot to restore, defaults to the latest snapshot")]
+        snapshot: Option<String>,
+    },
+    #[structopt(about = "List snapshots of the current branch")]
+    Log {
+        #[structopt(short = "n", long, about = "Maximum number of snapshots to list")]
+        max_count: Option<usize>,
+    },
+    #[structopt(
+        about = "Show changes between a snapshot and the working tree or another snapshot"
+    )]
+    Diff {
+        #[structopt(long, about = "Show a diffstat instead of a patch")]
+        stat: bool,
+        #[structopt(
+            long,
+            conflicts_with = "stat",
+            about = "Show only names of changed files"
+        )]
+        name_only: bool,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_duration),
+            conflicts_with = "snapshot",
+            about = "Diff against the latest snapshot older than this duration, e.g. 2h"
+        )]
+        before: Option<Duration>,
+        #[structopt(about = "Snapshot to diff from, defaults to the latest snapshot")]
+        snapshot: Option<String>,
+        #[structopt(about = "Snapshot to diff to, defaults to the working tree")]
+        other: Option<String>,
+    },
+    #[structopt(about = "Thin snapshot history of the current branch by the retention policy")]
+    Prune {
+        #[structopt(long, about = "Force push the pruned history to remotes")]
+        push: bool,
+    },
     #[structopt(about = "Runs the watcher in foreground")]
     StartWatcher {
         #[structopt(short, long, env = "GIT_SNAPSHOT_CONFIG", about = "config path")]
@@ -125,7 +181,14 @@ fn run(app: App) -> Result<(), Error> {
                 let _watcher = RepoWatcher::with_config(config.unwrap_or(default_config_path()?))?;
                 park();
             }
-            AppCommands::Watch { config, path } => {
+            AppCommands::Watch {
+                config,
+                init_shadow,
+                path,
+            } => {
+                if init_shadow {
+                    Repo::init_shadow(&path, &Config::open_default()?)?;
+                }
                 let p = config.unwrap_or(default_config_path()?);
                 let mut config = load_config(&p)?;
                 config.add_repo(path)?;
@@ -137,15 +200,164 @@ fn run(app: App) -> Result<(), Error> {
                 config.remove_repo(path)?;
                 save_config(&p, &config)?;
             }
+            AppCommands::Restore { force, snapshot } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let snapshot = repo.find_snapshot(snapshot.as_deref())?;
+                for path in repo.restore(&snapshot, force)? {
+                    println!("{}", path.display());
+                }
+            }
+            AppCommands::Log { max_count } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                for snapshot in repo.snapshots()?.take(max_count.unwrap_or(usize::MAX)) {
+                    let snapshot = snapshot?;
+                    let stats = repo.snapshot_stats(&snapshot)?;
+                    println!(
+                        "{} {} {}",
+                        snapshot
+                            .as_object()
+                            .short_id()?
+                            .as_str()
+                            .unwrap_or_default(),
+                        format_time(snapshot.time()),
+                        snapshot.summary().unwrap_or_default()
+                    );
+                    println!(
+                        " {} files changed, {} insertions(+), {} deletions(-)",
+                        stats.files_changed(),
+                        stats.insertions(),
+                        stats.deletions()
+                    );
+                }
+            }
+            AppCommands::Diff {
+                stat,
+                name_only,
+                before,
+                snapshot,
+                other,
+            } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                let snapshot = match before {
+                    Some(before) => repo.find_snapshot_before(SystemTime::now() - before)?,
+                    None => repo.find_snapshot(snapshot.as_deref())?,
+                };
+                let other = other.map(|o| repo.find_snapshot(Some(&o))).transpose()?;
+                let diff = repo.diff_snapshot(&snapshot, other.as_ref())?;
+                if stat {
+                    let stats = diff.stats()?.to_buf(DiffStatsFormat::FULL, 80)?;
+                    print!("{}", stats.as_str().unwrap_or_default());
+                } else if name_only {
+                    print_diff(&diff, DiffFormat::NameOnly)?;
+                } else {
+                    print_diff(&diff, DiffFormat::Patch)?;
+                }
+            }
+            AppCommands::Prune { push } => {
+                let repo = Repo::from_path(current_dir()?)?;
+                println!("pruned {} snapshots", repo.prune(push)?);
+            }
         }
     } else {
         let cwd = current_dir()?;
         let repo = Repo::from_path(cwd)?;
-        repo.snapshot()?;
+        if app.dry_run {
+            print_dry_run(&repo.dry_run()?);
+        } else {
+            // A snapshot that failed to push is still reported before the error
+            let result = repo.snapshot();
+            let outcome = match &result {
+                Ok(outcome) => Some(outcome),
+                Err(git_snapshot::Error::Push(outcome)) => Some(outcome.as_ref()),
+                Err(_) => None,
+            };
+            if let Some(outcome) = outcome {
+                if app.json {
+                    to_writer(std::io::stdout(), outcome)?;
+                    println!();
+                } else {
+                    print_outcome(outcome);
+                }
+            }
+            result?;
+        }
     }
     Ok(())
 }
 
+fn print_diff(diff: &Diff, format: DiffFormat) -> Result<(), Error> {
+    diff.print(format, |_, _, line| {
+        if let '+' | '-' | ' ' = line.origin() {
+            print!("{}", line.origin());
+        }
+        print!("{}", String::from_utf8_lossy(line.content()));
+        true
+    })?;
+    Ok(())
+}
+
+fn print_outcome(outcome: &SnapshotOutcome) {
+    match (outcome.commit, outcome.skip_reason) {
+        (Some(commit), _) => println!(
+            "snapshot {} {}: {} added, {} modified, {} deleted",
+            &commit.to_string()[..7],
+            outcome.ref_name,
+            outcome.added,
+            outcome.modified,
+            outcome.deleted
+        ),
+        (None, Some(SkipReason::Disabled)) => println!("snapshots are disabled for this branch"),
+        (None, Some(SkipReason::NoChanges)) => println!("no changes from the previous snapshot"),
+        (None, _) => println!("no snapshot taken"),
+    }
+    for push in &outcome.pushes {
+        match &push.error {
+            Some(err) => println!("failed to push to {}: {}", push.remote, err),
+            None => println!("pushed to {}", push.remote),
+        }
+    }
+}
+
+fn print_dry_run(dry_run: &DryRun) {
+    if !dry_run.enabled {
+        println!("snapshots are disabled for this branch");
+    }
+    println!("snapshot {}", dry_run.ref_name);
+    for (kind, path) in &dry_run.changes {
+        let status = match kind {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Deleted => 'D',
+        };
+        println!("{} {}", status, path.display());
+    }
+    if dry_run.changes.is_empty() && !dry_run.index_changed {
+        println!("no changes from the previous snapshot");
+    } else {
+        println!(
+            "{} files changed, {} bytes{}",
+            dry_run.changes.len(),
+            dry_run.size,
+            if dry_run.index_changed {
+                ", staged changes"
+            } else {
+                ""
+            }
+        );
+    }
+    for (path, size) in &dry_run.skipped {
+        println!("skipped {} ({} bytes)", path.display(), size);
+    }
+    for (remote, refspecs) in &dry_run.pushes {
+        println!("push {} {}", remote, refspecs.join(" "));
+    }
+}
+
+fn format_time(time: Time) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(time.seconds().max(0) as u64);
+    format_rfc3339_seconds(time).to_string()
+}
+
 fn default_config_path() -> Result<PathBuf, Error> {
     let home = dirs::home_dir().ok_or(anyhow!("Unable to get home directory"))?;
     Ok(home.join(
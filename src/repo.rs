@@ -1,110 +1,693 @@
-use crate::error::Error;
+use crate::error::{Error, PushError};
+use crate::filter::PathFilter;
+use crate::lfs::{is_lfs_path, LfsStore};
+use crate::retention::RetentionPolicy;
+use crate::shadow::{find_plain_shadow, init_plain_shadow, open_shadow};
+use crate::sign::Signer;
 
-use crate::util::{branch_ref_shorthand, expand, ConfigValue, BRANCH_REF_PREFIX};
+use crate::util::{
+    branch_ref_shorthand, common_dir, expand, format_time, hostname, stat_index_entry, trailer,
+    trailer_values, username, with_trailers, ConfigValue, BRANCH_REF_PREFIX,
+};
+use git2::build::{CheckoutBuilder, TreeUpdateBuilder};
 use git2::{
-    Config, Cred, ErrorCode, Index, IndexAddOption, PushOptions, RemoteCallbacks, Repository,
+    Commit, Config, Cred, Delta, Diff, DiffStats, ErrorCode, FileMode, Index, IndexAddOption,
+    IndexEntry, IndexTime, ObjectType, Oid, PushOptions, RemoteCallbacks, Repository,
+    RepositoryState, Signature, Submodule, SubmoduleIgnore, SubmoduleStatus, Tree, TreeWalkMode,
+    TreeWalkResult,
 };
-use log::{debug, error, info};
-use std::path::Path;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{create_dir_all, read_to_string, remove_dir, remove_file};
+use std::io::Write;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BRANCH_SUB_KEY: &str = "BRANCH";
+const SHA_SUB_KEY: &str = "SHA";
+const WORKTREE_SUB_KEY: &str = "WORKTREE";
+const TIMESTAMP_SUB_KEY: &str = "TIMESTAMP";
+const HOSTNAME_SUB_KEY: &str = "HOSTNAME";
+const USER_SUB_KEY: &str = "USER";
+const FILES_CHANGED_SUB_KEY: &str = "FILES_CHANGED";
+const CHANGED_PATHS_SUB_KEY: &str = "CHANGED_PATHS";
+const TRIGGER_SUB_KEY: &str = "TRIGGER";
+const DEFAULT_SNAPSHOT_BRANCH: &str = "${BRANCH}";
+const DEFAULT_DETACHED_SNAPSHOT_BRANCH: &str = "detached/${SHA}";
+// linked worktrees default to their own snapshot branches so they never share one
+const DEFAULT_WORKTREE_SNAPSHOT_BRANCH: &str = "worktrees/${WORKTREE}/${BRANCH}";
+const DEFAULT_WORKTREE_DETACHED_SNAPSHOT_BRANCH: &str = "worktrees/${WORKTREE}/detached/${SHA}";
+// snapshots are stored as branches unless configured otherwise, e.g. refs/snapshots/
+const DEFAULT_REF_NAMESPACE: &str = BRANCH_REF_PREFIX;
+// default snapshot branches are prefixed to tell them apart from the branches they snapshot
+const BRANCH_NAMESPACE_PREFIX: &str = "snapshot/";
+// ${WORKTREE} of the main worktree
+const MAIN_WORKTREE: &str = "main";
+const DEFAULT_SHADOW_DIR: &str = "~/.local/share/git-snapshot";
+// branch name used for config keys and templates when HEAD is detached
+const DETACHED_BRANCH: &str = "HEAD";
+const DEFAULT_SNAPSHOT_COMMIT_MESSAGE: &str = "Snapshot";
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+// changed paths listed in ${CHANGED_PATHS} before the rest are summarized
+const MAX_CHANGED_PATHS: usize = 5;
+const INDEX_TRAILER: &str = "Snapshot-Index";
+const BASE_TRAILER: &str = "Snapshot-Base";
+const SKIPPED_TRAILER: &str = "Snapshot-Skipped";
+const STATE_TRAILER: &str = "Snapshot-State";
+const INDEX_STAGE_SHIFT: u16 = 12;
+// index commit trailers of the commits holding the common ancestor and their side of conflicts
+const CONFLICT_TRAILERS: [(u16, &str); 2] = [
+    (1, "Snapshot-Conflict-Ancestor"),
+    (3, "Snapshot-Conflict-Theirs"),
+];
+const BACKUP_REF_PREFIX: &str = "refs/snapshot-backup/";
+// notes of each machine go to a ref of its own, e.g. refs/notes/snapshot/laptop, so machines
+// pushing to the same remote don't reject each other's notes
+const NOTES_REF_PREFIX: &str = "refs/notes/snapshot/";
+const MEMPACK_PRIORITY: i32 = 1000;
+// private indexes of the snapshot refs, relative to the git dir
+const INDEX_CACHE_DIR: &str = "snapshot-index";
+
+/// What happens to the snapshot chain once the branch gets a new commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Keep appending to the existing chain
+    Off,
+    /// Start a new chain, dropping the old one
+    Drop,
+    /// Start a new chain on top of a single commit archiving the old one
+    Archive,
+}
+
+impl ResetMode {
+    pub fn from_config(config: &Config, current_branch: &str) -> Self {
+        let mode = String::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotresetoncommit", current_branch),
+                "snapshot.resetoncommit",
+            ],
+            String::new(),
+        );
+        match mode.as_str() {
+            "drop" => Self::Drop,
+            "archive" => Self::Archive,
+            _ => Self::Off,
+        }
+    }
+}
+
+/// What caused a snapshot to be taken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// Requested from the command line or library
+    #[default]
+    Manual,
+    /// Taken by the watcher after files changed
+    Watcher,
+}
+
+impl Trigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Manual => "manual",
+            Self::Watcher => "watcher",
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// When failing to push snapshots to remotes fails the snapshot itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushFailurePolicy {
+    /// Push failures are only reported
+    Ignore,
+    /// Fail if any remote fails
+    Any,
+    /// Fail if every remote fails
+    All,
+}
+
+impl PushFailurePolicy {
+    /// Whether the push results fail the snapshot
+    pub fn fails(&self, pushes: &[PushResult]) -> bool {
+        let failures = pushes.iter().filter(|push| push.error.is_some()).count();
+        match self {
+            Self::Ignore => false,
+            Self::Any => failures > 0,
+            Self::All => failures > 0 && failures == pushes.len(),
+        }
+    }
+
+    pub fn from_config(config: &Config, current_branch: &str) -> Self {
+        let policy = String::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotfailonpush", current_branch),
+                "snapshot.failonpush",
+            ],
+            String::new(),
+        );
+        match policy.as_str() {
+            "any" => Self::Any,
+            "all" => Self::All,
+            _ => Self::Ignore,
+        }
+    }
+}
 
-const BRANCH_SUB_KEY: &'static str = "BRANCH";
-const DEFAULT_SNAPSHOT_BRANCH: &'static str = "snapshot/${BRANCH}";
-const DEFAULT_SNAPSHOT_COMMIT_MESSAGE: &'static str = "Snapshot";
+/// Options for taking a snapshot
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotOptions {
+    pub trigger: Trigger,
+    /// Paths whose changes triggered the snapshot, relative to the worktree
+    pub paths: Vec<PathBuf>,
+    /// Only log what the snapshot would contain, see `Repo::dry_run`
+    pub dry_run: bool,
+}
+
+/// How a path changes in a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// Why no snapshot was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    /// Snapshots are disabled for the branch
+    Disabled,
+    /// Nothing changed since the previous snapshot
+    NoChanges,
+    /// Only a dry run was requested
+    DryRun,
+}
+
+/// Result of pushing a snapshot to a remote
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PushResult {
+    pub remote: String,
+    /// Why the push failed, if it did
+    pub error: Option<PushError>,
+}
+
+/// What a snapshot did: the commit it created and where it was pushed, or why it was skipped
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SnapshotOutcome {
+    #[serde(serialize_with = "serialize_oid")]
+    pub commit: Option<Oid>,
+    pub ref_name: String,
+    pub added: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    pub skip_reason: Option<SkipReason>,
+    pub pushes: Vec<PushResult>,
+}
+
+impl SnapshotOutcome {
+    /// Remotes the snapshot failed to push to, with their errors
+    pub fn push_failures(&self) -> Vec<(&str, &PushError)> {
+        self.pushes
+            .iter()
+            .filter_map(|push| Some((push.remote.as_str(), push.error.as_ref()?)))
+            .collect()
+    }
+
+    fn count_changes(&mut self, changes: &[(ChangeKind, PathBuf)]) {
+        for (kind, _) in changes {
+            match kind {
+                ChangeKind::Added => self.added += 1,
+                ChangeKind::Modified => self.modified += 1,
+                ChangeKind::Deleted => self.deleted += 1,
+            }
+        }
+    }
+
+    fn skipped(ref_name: String, reason: SkipReason) -> Self {
+        Self {
+            ref_name,
+            skip_reason: Some(reason),
+            ..Default::default()
+        }
+    }
+}
+
+// Path of the file next to a private index naming the tree last written from it and the key
+// of the rules it was written with
+fn index_key_path(index_path: &Path) -> PathBuf {
+    let mut path = index_path.as_os_str().to_owned();
+    path.push(".filter");
+    PathBuf::from(path)
+}
+
+fn serialize_oid<S: Serializer>(oid: &Option<Oid>, serializer: S) -> Result<S::Ok, S::Error> {
+    oid.map(|oid| oid.to_string()).serialize(serializer)
+}
+
+/// What the next snapshot would contain, computed without writing any objects or refs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DryRun {
+    /// Whether snapshots are enabled for the branch
+    pub enabled: bool,
+    pub ref_name: String,
+    /// Changes from the previous snapshot
+    pub changes: Vec<(ChangeKind, PathBuf)>,
+    /// Whether the staged changes differ from the previous snapshot
+    pub index_changed: bool,
+    /// Total size of the added and modified files
+    pub size: u64,
+    pub skipped: Vec<(PathBuf, u64)>,
+    /// Remotes the snapshot would be pushed to, with their refspecs
+    pub pushes: Vec<(String, Vec<String>)>,
+}
+
+/// Where a snapshot was taken, stored as a JSON note under `refs/notes/snapshot/<host>`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotMetadata {
+    pub hostname: String,
+    pub user: String,
+    pub version: String,
+    pub trigger: Trigger,
+    pub paths: Vec<PathBuf>,
+}
 
 pub struct Repo {
+    // repository snapshots are stored in, the project itself unless a shadow is configured
     git_repo: Repository,
+    // project repository whose HEAD, index and config are snapshotted when they are stored in
+    // a shadow repository
+    project: Option<Repository>,
+    // branch to snapshot for instead of HEAD, used for submodules
+    branch: Option<String>,
 }
 
 // TODO: add config setter helper functions
 impl Repo {
     pub fn new(repo: Repository) -> Self {
-        Repo { git_repo: repo }
+        Repo {
+            git_repo: repo,
+            project: None,
+            branch: None,
+        }
+    }
+
+    /// Opens a repository for snapshotting, in its shadow repository if `snapshot.shadow` is set
+    pub fn open(repo: Repository) -> Result<Self, Error> {
+        let config = repo.config()?;
+        if !bool::from_config(&config, &["snapshot.shadow"], false) {
+            return Ok(Self::new(repo));
+        }
+        Ok(Repo {
+            git_repo: open_shadow(&repo, &Self::shadow_dir(&config))?,
+            project: Some(repo),
+            branch: None,
+        })
     }
 
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self, Error> {
-        let git_repo = Repository::discover(path)?;
-        Ok(Self::new(git_repo))
+        match Repository::discover(path.as_ref()) {
+            Ok(git_repo) => Self::open(git_repo),
+            // Plain directories are snapshotted in their shadow repository, if one was created
+            Err(err) if err.code() == ErrorCode::NotFound => {
+                Self::from_plain_path(path, &Config::open_default()?).ok_or_else(|| err.into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Opens the shadow repository of a plain directory created by `init_shadow`
+    pub fn from_plain_path(path: impl AsRef<Path>, config: &Config) -> Option<Self> {
+        find_plain_shadow(&Self::shadow_dir(config), path.as_ref()).map(Self::new)
+    }
+
+    /// Creates a shadow repository for a plain directory that isn't a git repository, so it
+    /// can be snapshotted and watched like one
+    pub fn init_shadow(path: impl AsRef<Path>, config: &Config) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if Repository::discover(path).is_ok() {
+            return Err(git2::Error::from_str(
+                "already a git repository, set snapshot.shadow to snapshot it into a shadow",
+            )
+            .into());
+        }
+        Ok(Self::new(init_plain_shadow(
+            &Self::shadow_dir(config),
+            path,
+        )?))
+    }
+
+    /// Directory shadow repositories are kept in, `~/.local/share/git-snapshot` by default
+    pub fn shadow_dir(config: &Config) -> PathBuf {
+        let dir = String::from_config(
+            config,
+            &["snapshot.shadowdir"],
+            DEFAULT_SHADOW_DIR.to_owned(),
+        );
+        PathBuf::from(shellexpand::tilde(&dir).as_ref())
     }
 
+    /// Repository snapshots are stored in
     pub fn git_repo(&self) -> &Repository {
         &self.git_repo
     }
 
+    /// Repository being snapshotted, which differs from `git_repo` when snapshots are stored
+    /// in a shadow repository
+    pub fn project_repo(&self) -> &Repository {
+        self.project.as_ref().unwrap_or(&self.git_repo)
+    }
+
+    /// Whether snapshots are stored in a shadow repository
+    pub fn is_shadow(&self) -> bool {
+        self.project.is_some()
+    }
+
     pub fn name(&self) -> &str {
-        let mut components = self.git_repo.path().components();
-        components.next_back();
-        components
+        // named after the worktree, git dirs may be kept outside of it
+        let repo = self.project_repo();
+        let path = match repo.workdir() {
+            Some(workdir) => workdir,
+            None => repo.path().parent().unwrap_or(repo.path()),
+        };
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("unknown")
+    }
+
+    /// Name of the linked worktree this repo is opened at, `None` for the main worktree
+    pub fn worktree_name(&self) -> Option<&str> {
+        if !self.project_repo().is_worktree() {
+            return None;
+        }
+        // linked worktree git dirs are `<common git dir>/worktrees/<name>/`
+        self.project_repo()
+            .path()
+            .components()
             .next_back()
             .and_then(|c| c.as_os_str().to_str())
-            .unwrap_or("unknown")
     }
 
-    pub fn snapshot_branch(config: &Config, current_branch: &str) -> String {
+    /// The main worktree and all linked worktrees of the repository
+    pub fn worktrees(&self) -> Result<Vec<Repo>, Error> {
+        let main_repo = Repository::open(common_dir(self.project_repo().path()))?;
+        let mut worktrees = Vec::new();
+        for name in main_repo.worktrees()?.iter().flatten() {
+            let worktree = main_repo.find_worktree(name)?;
+            // skip worktrees whose directory is gone
+            if worktree.validate().is_err() {
+                debug!(target: self.name(), "skipping invalid worktree: {}", name);
+                continue;
+            }
+            worktrees.push(Repo::open(Repository::open_from_worktree(&worktree)?)?);
+        }
+        if !main_repo.is_bare() {
+            worktrees.insert(0, Repo::open(main_repo)?);
+        }
+        Ok(worktrees)
+    }
+
+    /// Ref namespace snapshots are stored under, locally and on remotes, e.g. `refs/snapshots/`
+    pub fn ref_namespace(config: &Config) -> String {
+        let namespace = String::from_config(
+            config,
+            &["snapshot.refnamespace"],
+            DEFAULT_REF_NAMESPACE.to_owned(),
+        );
+        let namespace = namespace.trim_end_matches('/');
+        if namespace.is_empty() {
+            return DEFAULT_REF_NAMESPACE.to_owned();
+        }
+        format!("{}/", namespace)
+    }
+
+    // defaults are relative to the namespace, only branches get the `snapshot/` prefix
+    fn default_snapshot_branch(namespace: &str, default: &str) -> String {
+        if namespace == BRANCH_REF_PREFIX {
+            [BRANCH_NAMESPACE_PREFIX, default].concat()
+        } else {
+            default.to_owned()
+        }
+    }
+
+    /// Full snapshot ref name of a branch, e.g. `refs/heads/snapshot/main`
+    pub fn snapshot_branch(
+        config: &Config,
+        current_branch: &str,
+        worktree: Option<&str>,
+    ) -> String {
+        let namespace = Self::ref_namespace(config);
+        let default = match worktree {
+            Some(_) => DEFAULT_WORKTREE_SNAPSHOT_BRANCH,
+            None => DEFAULT_SNAPSHOT_BRANCH,
+        };
         let snapshot_branch = String::from_config(
-            &config,
+            config,
             &[
                 &format!("branch.{}.snapshotbranch", current_branch),
                 "snapshot.snapshotbranch",
             ],
-            DEFAULT_SNAPSHOT_BRANCH.to_owned(),
+            Self::default_snapshot_branch(&namespace, default),
+        );
+        let snapshot_branch = expand(
+            &snapshot_branch,
+            &[
+                (BRANCH_SUB_KEY, current_branch),
+                (WORKTREE_SUB_KEY, worktree.unwrap_or(MAIN_WORKTREE)),
+            ],
         );
-        expand(&snapshot_branch, &[(BRANCH_SUB_KEY, &current_branch)])
+        [namespace, snapshot_branch].concat()
     }
 
-    pub fn snapshot(&self) -> Result<(), Error> {
-        let current_branch = self.current_branch()?;
-        let config = self.git_repo.config()?;
-
-        // Check if snapshotting is enabled for the current branch
-        let enabled = bool::from_config(
-            &config,
-            &[&format!("branch.{}.snapshotenabled", current_branch)],
-            true,
+    /// Full snapshot ref name used while HEAD is detached, `${SHA}` expands to the HEAD commit
+    pub fn detached_snapshot_branch(config: &Config, sha: &str, worktree: Option<&str>) -> String {
+        let namespace = Self::ref_namespace(config);
+        let default = match worktree {
+            Some(_) => DEFAULT_WORKTREE_DETACHED_SNAPSHOT_BRANCH,
+            None => DEFAULT_DETACHED_SNAPSHOT_BRANCH,
+        };
+        let snapshot_branch = String::from_config(
+            config,
+            &["snapshot.detachedbranch"],
+            Self::default_snapshot_branch(&namespace, default),
+        );
+        let snapshot_branch = expand(
+            &snapshot_branch,
+            &[
+                (SHA_SUB_KEY, sha),
+                (WORKTREE_SUB_KEY, worktree.unwrap_or(MAIN_WORKTREE)),
+            ],
         );
+        [namespace, snapshot_branch].concat()
+    }
+
+    // Branch to snapshot for and its snapshot ref name. A detached HEAD, e.g. during a rebase
+    // or bisect, is snapshotted as the HEAD branch into the detached snapshot branch
+    fn snapshot_target(&self, config: &Config) -> Result<(String, String), Error> {
+        match self.current_branch() {
+            Ok(current_branch) => {
+                let snapshot_ref_name =
+                    Self::snapshot_branch(config, &current_branch, self.worktree_name());
+                Ok((current_branch, snapshot_ref_name))
+            }
+            Err(Error::InvalidHead) if self.project_repo().head_detached().unwrap_or(false) => {
+                let sha = self
+                    .project_repo()
+                    .head()?
+                    .peel_to_commit()?
+                    .id()
+                    .to_string();
+                Ok((
+                    DETACHED_BRANCH.to_owned(),
+                    Self::detached_snapshot_branch(config, &sha, self.worktree_name()),
+                ))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // commit HEAD points at, the snapshot is taken on top of it
+    fn head_commit(&self) -> Option<Commit<'_>> {
+        let id = self.project_repo().head().ok()?.target()?;
+        self.git_repo.find_commit(id).ok()
+    }
+
+    // in-progress operation recorded in the snapshot message
+    fn operation_state(&self) -> Option<&'static str> {
+        match self.project_repo().state() {
+            RepositoryState::Clean => None,
+            RepositoryState::Merge => Some("merge"),
+            RepositoryState::Revert | RepositoryState::RevertSequence => Some("revert"),
+            RepositoryState::CherryPick | RepositoryState::CherryPickSequence => {
+                Some("cherry-pick")
+            }
+            RepositoryState::Bisect => Some("bisect"),
+            RepositoryState::Rebase
+            | RepositoryState::RebaseInteractive
+            | RepositoryState::RebaseMerge => Some("rebase"),
+            RepositoryState::ApplyMailbox | RepositoryState::ApplyMailboxOrRebase => Some("am"),
+        }
+    }
+
+    pub fn snapshot(&self) -> Result<SnapshotOutcome, Error> {
+        self.snapshot_with(&SnapshotOptions::default())
+    }
+
+    pub fn snapshot_with(&self, options: &SnapshotOptions) -> Result<SnapshotOutcome, Error> {
+        let config = self.project_repo().config()?;
+        let (current_branch, snapshot_ref_name) = self.snapshot_target(&config)?;
+
+        if options.dry_run {
+            let dry_run = self.dry_run()?;
+            info!(
+                target: self.name(),
+                "dry run: {} changed files ({} bytes) for {}",
+                dry_run.changes.len(),
+                dry_run.size,
+                dry_run.ref_name
+            );
+            let mut outcome = SnapshotOutcome::skipped(dry_run.ref_name, SkipReason::DryRun);
+            outcome.count_changes(&dry_run.changes);
+            return Ok(outcome);
+        }
 
-        if !enabled {
+        // Check if snapshotting is enabled for the current branch
+        if !Self::snapshot_enabled(&config, &current_branch) {
             info!(
                 target: self.name(),
                 "snapshots disabled for branch: {}",
                 current_branch
             );
-            return Ok(());
+            return Ok(SnapshotOutcome::skipped(
+                snapshot_ref_name,
+                SkipReason::Disabled,
+            ));
         }
 
-        let snapshot_branch = Self::snapshot_branch(&config, &current_branch);
-
-        // create full branch ref name, e.g. refs/heads/snapshot/main
-        let snapshot_ref_name = [BRANCH_REF_PREFIX, &snapshot_branch].concat();
-
-        // Build the index with the current local changes and write to repo
-        let mut index = Index::new()?;
-        self.git_repo.set_index(&mut index)?;
-        index.add_all(&["*"], IndexAddOption::DEFAULT, None)?;
-
-        let tree = index.write_tree()?;
+        // Build a tree with the current local changes, leaving the repo index untouched
+        let filter = PathFilter::from_config(&config, &current_branch)?;
+        let (tree, skipped) = self.snapshot_tree(&filter, options)?;
         let tree = self.git_repo.find_tree(tree)?;
 
+        // Build a separate tree of the staged changes so both can be restored
+        let (index_tree, conflict_trees) = self.index_tree(&self.git_repo)?;
+        let index_tree = self.git_repo.find_tree(index_tree)?;
+
         // Get the current reference to the destination snapshot branch for diffing and the commit parent
         let snapshot_ref = self.git_repo.find_reference(&snapshot_ref_name).ok();
+        let parent = snapshot_ref.and_then(|r| r.peel_to_commit().ok());
 
-        // Diff the current index to the previous snapshot commit tree to check for changes
+        // Diff the current worktree and index to the previous snapshot trees to check for changes
         let diff = self.git_repo.diff_tree_to_tree(
-            snapshot_ref
-                .as_ref()
-                .and_then(|r| r.peel_to_tree().ok())
-                .as_ref(),
+            parent.as_ref().and_then(|c| c.tree().ok()).as_ref(),
             Some(&tree),
             None,
         )?;
-        if diff.deltas().next().is_none() {
+        let parent_index_tree = parent
+            .as_ref()
+            .and_then(|c| self.snapshot_index(c).ok().flatten())
+            .map(|c| c.tree_id());
+        if diff.deltas().next().is_none() && parent_index_tree == Some(index_tree.id()) {
             info!(target: self.name(), "No changes from previous snapshot, aborting snapshot");
-            return Ok(());
+            return Ok(SnapshotOutcome::skipped(
+                snapshot_ref_name,
+                SkipReason::NoChanges,
+            ));
         }
 
-        // Default signature from config
-        let signature = self.git_repo.signature()?;
+        // Only warn about files that weren't already skipped by the previous snapshot
+        let previously_skipped: Vec<PathBuf> = parent
+            .as_ref()
+            .map(|c| self.snapshot_skipped(c))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        for (path, size) in &skipped {
+            if !previously_skipped.contains(path) {
+                warn!(
+                    target: self.name(),
+                    "skipping {} ({} bytes), larger than the maximum snapshot file size",
+                    path.display(),
+                    size
+                );
+            }
+        }
 
-        let parent = snapshot_ref.and_then(|r| r.peel_to_commit().ok());
+        let signature = Self::snapshot_signature(&config, &current_branch)?;
+
+        // Sign snapshot commits if configured, e.g. with commit.gpgsign
+        let signer = Signer::from_config(&config, &current_branch);
+
+        // The commit the branch currently points at, the snapshot is taken on top of it
+        let base = self.head_commit();
+
+        // Start a fresh snapshot chain if configured and the branch moved since the previous snapshot
+        let reset_mode = ResetMode::from_config(&config, &current_branch);
+        let reset = self.resets(reset_mode, parent.as_ref(), base.as_ref())?;
+        let parent = match parent {
+            Some(previous) if reset => {
+                info!(
+                    target: self.name(),
+                    "branch moved, resetting snapshot branch: {}", current_branch
+                );
+                match reset_mode {
+                    ResetMode::Archive => {
+                        Some(self.archive_snapshots(&previous, &signature, signer.as_ref())?)
+                    }
+                    _ => None,
+                }
+            }
+            parent => parent,
+        };
+
+        // The other sides of conflicts get commits of their own, parents of the index commit
+        let mut conflict_commits = Vec::new();
+        let mut index_trailers = Vec::new();
+        for ((stage, key), tree) in CONFLICT_TRAILERS.iter().zip(conflict_trees) {
+            let id = self.create_commit(
+                signer.as_ref(),
+                &signature,
+                &signature,
+                &format!("conflict stage {} on {}", stage, current_branch),
+                &self.git_repo.find_tree(tree)?,
+                &[],
+            )?;
+            index_trailers.push((*key, id.to_string()));
+            conflict_commits.push(self.git_repo.find_commit(id)?);
+        }
+        let index_message = format!("index on {}", current_branch);
+        let index_message = if index_trailers.is_empty() {
+            index_message
+        } else {
+            with_trailers(&index_message, &index_trailers)
+        };
+        // A shadow repository only borrows the project's objects, which the project may garbage
+        // collect, so there the base commit is only named by its trailer and not linked
+        let base_parent = base.as_ref().filter(|_| !self.is_shadow());
+        let index_parents: Vec<&Commit> =
+            base_parent.into_iter().chain(&conflict_commits).collect();
+        let index_commit = self.create_commit(
+            signer.as_ref(),
+            &signature,
+            &signature,
+            &index_message,
+            &index_tree,
+            &index_parents,
+        )?;
+        let index_commit = self.git_repo.find_commit(index_commit)?;
 
         let message = String::from_config(
             &config,
@@ -114,338 +697,3304 @@ impl Repo {
             ],
             DEFAULT_SNAPSHOT_COMMIT_MESSAGE.to_owned(),
         );
-        self.git_repo.commit(
-            Some(&snapshot_ref_name),
+        let timestamp_format = String::from_config(
+            &config,
+            &[
+                &format!("branch.{}.snapshottimestampformat", current_branch),
+                "snapshot.timestampformat",
+            ],
+            DEFAULT_TIMESTAMP_FORMAT.to_owned(),
+        );
+        let changed_paths = Self::changed_paths(&diff);
+        let message = expand(
+            &message,
+            &[
+                (BRANCH_SUB_KEY, &current_branch),
+                (
+                    TIMESTAMP_SUB_KEY,
+                    &format_time(signature.when(), &timestamp_format),
+                ),
+                (HOSTNAME_SUB_KEY, &hostname()),
+                (USER_SUB_KEY, &username()),
+                (FILES_CHANGED_SUB_KEY, &diff.deltas().len().to_string()),
+                (CHANGED_PATHS_SUB_KEY, &changed_paths),
+                (TRIGGER_SUB_KEY, options.trigger.as_str()),
+            ],
+        );
+        let mut trailers = vec![(INDEX_TRAILER, index_commit.id().to_string())];
+        if let Some(base) = &base {
+            trailers.push((BASE_TRAILER, base.id().to_string()));
+        }
+        if let Some(state) = self.operation_state() {
+            trailers.push((STATE_TRAILER, state.to_owned()));
+        }
+        for (path, size) in &skipped {
+            trailers.push((SKIPPED_TRAILER, format!("{} {}", size, path.display())));
+        }
+        let message = with_trailers(&message, &trailers);
+
+        // The previous snapshot stays the first parent, followed by the index and base commits
+        let parents: Vec<&Commit> = parent
+            .iter()
+            .chain(once(&index_commit))
+            .chain(base_parent)
+            .collect();
+        let id = self.create_commit(
+            signer.as_ref(),
             &signature,
             &signature,
             &message,
             &tree,
-            parent
-                .as_ref()
-                .as_ref()
-                .map(std::slice::from_ref)
-                .unwrap_or_default(),
+            &parents,
         )?;
 
+        let previous_trees = match &parent {
+            Some(previous) => (
+                Some(previous.tree()?),
+                self.snapshot_index(previous)?
+                    .map(|c| c.tree())
+                    .transpose()?,
+            ),
+            None => (None, None),
+        };
+        let mut trees = vec![
+            (tree.clone(), previous_trees.0),
+            (index_tree.clone(), previous_trees.1),
+        ];
+        for conflict_commit in &conflict_commits {
+            trees.push((conflict_commit.tree()?, None));
+        }
+        self.copy_shadow_objects(&trees)?;
+        if reset {
+            // The new chain doesn't descend from the current tip, so the ref is replaced
+            self.git_repo
+                .reference(&snapshot_ref_name, id, true, "snapshot: reset")?;
+            self.remove_stale_index_caches();
+        } else if let Some(previous) = &parent {
+            // Only move the ref if no other snapshot was taken in the meantime
+            self.git_repo.reference_matching(
+                &snapshot_ref_name,
+                id,
+                true,
+                previous.id(),
+                "snapshot",
+            )?;
+        } else {
+            self.git_repo
+                .reference(&snapshot_ref_name, id, false, "snapshot: created")?;
+        }
+
+        // Record where the snapshot came from if enabled
+        if Self::notes_enabled(&config, &current_branch) {
+            let metadata = SnapshotMetadata {
+                hostname: Self::notes_host(&config),
+                user: username(),
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                trigger: options.trigger,
+                paths: options.paths.clone(),
+            };
+            self.git_repo.note(
+                &signature,
+                &signature,
+                Some(&Self::notes_ref(&config)),
+                id,
+                &serde_json::to_string_pretty(&metadata)?,
+                true,
+            )?;
+        }
+
         info!(
             target: self.name(),
             "snapshotted branch: {}", current_branch
         );
 
-        self.push(&snapshot_ref_name, &current_branch, &config)
+        let pushes = self.push(&snapshot_ref_name, &current_branch, &config, reset)?;
+        let mut outcome = SnapshotOutcome {
+            commit: Some(id),
+            ref_name: snapshot_ref_name,
+            pushes,
+            ..Default::default()
+        };
+        outcome.count_changes(&Self::diff_changes(&self.git_repo, &diff).0);
+
+        // Fail the snapshot if the push failure policy says so, it's saved either way
+        if PushFailurePolicy::from_config(&config, &current_branch).fails(&outcome.pushes) {
+            return Err(Error::Push(Box::new(outcome)));
+        }
+        Ok(outcome)
     }
 
-    fn push(&self, ref_name: &str, current_branch: &str, config: &Config) -> Result<(), Error> {
-        let remotes = self.git_repo.remotes()?;
+    // Whether the next snapshot starts a fresh chain instead of following the previous snapshot,
+    // which it does once the branch moved if configured
+    fn resets(
+        &self,
+        reset_mode: ResetMode,
+        previous: Option<&Commit>,
+        base: Option<&Commit>,
+    ) -> Result<bool, Error> {
+        Ok(match previous {
+            Some(previous) if reset_mode != ResetMode::Off => {
+                self.snapshot_base(previous)?.map(|c| c.id()) != base.map(|c| c.id())
+            }
+            _ => false,
+        })
+    }
 
-        for remote in &remotes {
-            let remote = remote.unwrap();
+    /// Computes what the next snapshot of the current branch would contain and where it would
+    /// be pushed, without writing anything to the repository
+    pub fn dry_run(&self) -> Result<DryRun, Error> {
+        let config = self.project_repo().config()?;
+        let (current_branch, snapshot_ref_name) = self.snapshot_target(&config)?;
 
-            // Check remote config if snapshots are enabled, disabled by default
-            let enabled = bool::from_config(
-                &config,
-                &[&format!("remote.{}.snapshotenabled", remote)],
-                false,
-            );
+        // Objects are only written to memory, so the trees are read through the same handle
+        let private_repo = self.private_repo(true)?;
+        let filter = PathFilter::from_config(&config, &current_branch)?;
+        let (tree, skipped) = self.worktree_tree_in(&private_repo, &filter, true)?;
+        let (index_tree, _) = self.index_tree(&private_repo)?;
 
-            if !enabled {
-                debug!(
-                    target: self.name(),
-                    "snapshots disabled for remote: {}",
-                    remote
-                );
-                continue;
-            }
+        let parent = private_repo
+            .find_reference(&snapshot_ref_name)
+            .and_then(|r| r.peel_to_commit())
+            .ok();
+        let diff = private_repo.diff_tree_to_tree(
+            parent.as_ref().and_then(|c| c.tree().ok()).as_ref(),
+            Some(&private_repo.find_tree(tree)?),
+            None,
+        )?;
 
-            // Get remote snapshot branch from remote config or default to the local snapshot branch
-            let snapshot_branch = String::from_config(
-                &config,
-                &[&format!("remote.{}.snapshotbranch", remote)],
-                branch_ref_shorthand(ref_name).to_owned(),
-            );
+        let (changes, size) = Self::diff_changes(&private_repo, &diff);
 
-            let snapshot_ref_name = [BRANCH_REF_PREFIX, &snapshot_branch].concat();
+        let parent_index_tree = parent
+            .as_ref()
+            .and_then(|c| self.snapshot_index(c).ok().flatten())
+            .map(|c| c.tree_id());
+        let notes = Self::notes_enabled(&config, &current_branch)
+            || self
+                .git_repo
+                .find_reference(&Self::notes_ref(&config))
+                .is_ok();
+        // A reset replaces the snapshot ref, which is force pushed
+        let reset = self.resets(
+            ResetMode::from_config(&config, &current_branch),
+            parent.as_ref(),
+            self.head_commit().as_ref(),
+        )?;
 
-            let snapshot_ref_name = expand(&snapshot_ref_name, &[(BRANCH_SUB_KEY, current_branch)]);
+        Ok(DryRun {
+            enabled: Self::snapshot_enabled(&config, &current_branch),
+            pushes: self.push_refspecs(
+                &snapshot_ref_name,
+                &current_branch,
+                &config,
+                reset,
+                notes,
+            )?,
+            ref_name: snapshot_ref_name,
+            changes,
+            index_changed: parent_index_tree != Some(index_tree),
+            size,
+            skipped,
+        })
+    }
 
-            let mut remote = self.git_repo.find_remote(&remote)?;
+    // Paths changed by a diff of snapshot trees, with the total size of the added and modified files
+    fn diff_changes(repo: &Repository, diff: &Diff) -> (Vec<(ChangeKind, PathBuf)>, u64) {
+        let mut changes = Vec::new();
+        let mut size = 0;
+        for delta in diff.deltas() {
+            let kind = match delta.status() {
+                Delta::Added | Delta::Untracked | Delta::Copied => ChangeKind::Added,
+                Delta::Deleted => ChangeKind::Deleted,
+                _ => ChangeKind::Modified,
+            };
+            let file = match kind {
+                ChangeKind::Deleted => delta.old_file(),
+                _ => delta.new_file(),
+            };
+            if kind != ChangeKind::Deleted {
+                // submodule gitlinks have no blob
+                size += repo
+                    .find_blob(file.id())
+                    .map_or(0, |blob| blob.size() as u64);
+            }
+            if let Some(path) = file.path() {
+                changes.push((kind, path.to_path_buf()));
+            }
+        }
+        (changes, size)
+    }
 
-            let config = config.clone();
+    // Check if snapshotting is enabled for a branch
+    fn snapshot_enabled(config: &Config, current_branch: &str) -> bool {
+        bool::from_config(
+            config,
+            &[&format!("branch.{}.snapshotenabled", current_branch)],
+            true,
+        )
+    }
 
-            let mut callbacks = RemoteCallbacks::new();
+    // Check if snapshots of a branch get metadata notes
+    fn notes_enabled(config: &Config, current_branch: &str) -> bool {
+        bool::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotnotes", current_branch),
+                "snapshot.notes",
+            ],
+            false,
+        )
+    }
 
-            // Only allow non-interactive credentials
-            // TODO: Look into using default ssh key
-            callbacks.credentials(move |url, username, allowed_types| {
-                if allowed_types.is_user_pass_plaintext() {
-                    if let Ok(cred) = Cred::credential_helper(&config, url, username) {
-                        return Ok(cred);
-                    }
-                }
-                if allowed_types.is_ssh_key() {
-                    if let Some(username) = username {
-                        if let Ok(cred) = Cred::ssh_key_from_agent(username) {
-                            return Ok(cred);
-                        }
-                    }
-                }
-                Err(git2::Error::new(
-                    git2::ErrorCode::Auth,
-                    git2::ErrorClass::Callback,
-                    "unable to authenticate, setup ssh key agent or credential helper for this remote and username",
-                ))
-            });
+    // Name of this machine in snapshot notes, `snapshot.hostname` or the hostname
+    fn notes_host(config: &Config) -> String {
+        String::from_config(config, &["snapshot.hostname"], hostname())
+    }
 
-            let mut opts = PushOptions::new();
-            opts.remote_callbacks(callbacks);
-            if let Err(err) =
-                remote.push(&[[ref_name, &snapshot_ref_name].join(":")], Some(&mut opts))
-            {
-                error!(
-                    target: self.name(),
-                    "error pushing snapshot branch to remote: {:?}",
-                    err
-                );
-            } else {
-                info!(
-                    target: self.name(),
-                    "pushed snapshot branch to remote: {}",
-                    remote.name().unwrap_or("unknown")
-                );
-            }
-        }
-        Ok(())
+    // Notes ref of this machine, its name reduced to characters that are safe in a ref name
+    fn notes_ref(config: &Config) -> String {
+        let host: String = Self::notes_host(config)
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '-',
+            })
+            .collect();
+        [NOTES_REF_PREFIX, &host].concat()
     }
 
-    pub fn current_branch(&self) -> Result<String, Error> {
-        match self.git_repo.head() {
-            Ok(reference) => {
-                if !reference.is_branch() || reference.is_remote() {
-                    return Err(Error::InvalidHead);
-                }
-                reference
-                    .shorthand()
-                    .map(|r| r.to_owned())
-                    .ok_or(Error::InvalidHead)
-            }
-            Err(err) => {
-                if err.code() == ErrorCode::UnbornBranch {
-                    let reference = self.git_repo.find_reference("HEAD")?;
-                    let target = reference.symbolic_target().ok_or(Error::InvalidHead)?;
-                    return Ok(branch_ref_shorthand(target).to_owned());
+    // Notes refs of all machines, this machine's first
+    fn notes_refs(&self, config: &Config) -> Result<Vec<String>, Error> {
+        let own = Self::notes_ref(config);
+        let mut refs = vec![own.clone()];
+        for reference in self
+            .git_repo
+            .references_glob(&format!("{}*", NOTES_REF_PREFIX))?
+        {
+            if let Some(name) = reference?.name() {
+                if name != own {
+                    refs.push(name.to_owned());
                 }
-                Err(Error::InvalidHead)
             }
         }
+        Ok(refs)
     }
 
-    pub fn is_ignored(&self, path: &Path) -> Result<bool, Error> {
-        Ok(self.git_repo.is_path_ignored(path)?)
+    /// Returns the metadata note of a snapshot, if one was written on this or another machine
+    pub fn snapshot_metadata(&self, snapshot: &Commit) -> Result<Option<SnapshotMetadata>, Error> {
+        for notes_ref in self.notes_refs(&self.project_repo().config()?)? {
+            match self.git_repo.find_note(Some(&notes_ref), snapshot.id()) {
+                Ok(note) => return Ok(Some(serde_json::from_slice(note.message_bytes())?)),
+                Err(err) if err.code() == ErrorCode::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(None)
     }
-}
 
-#[cfg(test)]
-pub mod tests {
+    /// Identity of snapshot commits, from `snapshot.user.name` and `snapshot.user.email` or the
+    /// regular git identity, falling back to the OS user on this host
+    pub fn snapshot_signature(
+        config: &Config,
+        current_branch: &str,
+    ) -> Result<Signature<'static>, Error> {
+        let name = String::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotusername", current_branch),
+                "snapshot.user.name",
+                "user.name",
+            ],
+            username(),
+        );
+        let email = String::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotuseremail", current_branch),
+                "snapshot.user.email",
+                "user.email",
+            ],
+            format!("{}@{}", username(), hostname()),
+        );
+        Ok(Signature::now(&name, &email)?)
+    }
+
+    // short list of the paths in a diff for the snapshot message, e.g. `a, b (+3 more)`
+    fn changed_paths(diff: &Diff) -> String {
+        let paths: Vec<String> = diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+            .map(|path| path.display().to_string())
+            .collect();
+        let mut changed_paths = paths
+            .iter()
+            .take(MAX_CHANGED_PATHS)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if paths.len() > MAX_CHANGED_PATHS {
+            changed_paths.push_str(&format!(" (+{} more)", paths.len() - MAX_CHANGED_PATHS));
+        }
+        changed_paths
+    }
+
+    // Collapses a snapshot chain into a single commit holding its latest tree on top of its base
+    fn archive_snapshots(
+        &self,
+        tip: &Commit,
+        signature: &Signature,
+        signer: Option<&Signer>,
+    ) -> Result<Commit<'_>, Error> {
+        let count = self.snapshot_chain(tip).count();
+        let base = self.snapshot_base(tip)?;
+        let base_parent = base.as_ref().filter(|_| !self.is_shadow());
+
+        let mut message = format!("Archive of {} snapshots", count);
+        if let Some(base) = &base {
+            message = with_trailers(&message, &[(BASE_TRAILER, base.id().to_string())]);
+        }
+        let id = self.create_commit(
+            signer,
+            signature,
+            signature,
+            &message,
+            &tip.tree()?,
+            base_parent.as_slice(),
+        )?;
+        Ok(self.git_repo.find_commit(id)?)
+    }
+
+    // A shadow repository reads unchanged files from the project's objects, which a gc of the
+    // project may remove. Objects of the new snapshot trees that weren't written loose to the
+    // shadow are copied into a pack of its own, subtrees unchanged from the previous trees have
+    // been copied already and are skipped.
+    fn copy_shadow_objects(&self, trees: &[(Tree, Option<Tree>)]) -> Result<(), Error> {
+        if !self.is_shadow() {
+            return Ok(());
+        }
+        // the shadow's odb also reads the project's objects, so only loose files tell them apart
+        let objects = self.git_repo.path().join("objects");
+        let mut missing = Vec::new();
+        for (tree, previous) in trees {
+            self.missing_objects(&objects, tree, previous.as_ref(), &mut missing)?;
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        missing.sort();
+        missing.dedup();
+        debug!(
+            target: self.name(),
+            "copying {} project objects into the shadow",
+            missing.len()
+        );
+
+        let mut builder = self.git_repo.packbuilder()?;
+        for id in missing {
+            builder.insert_object(id, None)?;
+        }
+        let odb = self.git_repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        let mut written = Ok(());
+        builder.foreach(|bytes| {
+            written = writer.write_all(bytes);
+            written.is_ok()
+        })?;
+        written?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    // Collects objects of a tree that aren't loose in `objects` or in the previous tree
+    fn missing_objects(
+        &self,
+        objects: &Path,
+        tree: &Tree,
+        previous: Option<&Tree>,
+        missing: &mut Vec<Oid>,
+    ) -> Result<(), Error> {
+        let is_loose = |id: Oid| {
+            let hex = id.to_string();
+            objects.join(&hex[..2]).join(&hex[2..]).is_file()
+        };
+        if previous.map(Tree::id) == Some(tree.id()) {
+            return Ok(());
+        }
+        if !is_loose(tree.id()) {
+            missing.push(tree.id());
+        }
+        for entry in tree.iter() {
+            let previous_id = previous
+                .and_then(|previous| previous.get_name(entry.name()?))
+                .map(|entry| entry.id());
+            if previous_id == Some(entry.id()) {
+                continue;
+            }
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    let subtree = self.git_repo.find_tree(entry.id())?;
+                    let previous_subtree =
+                        previous_id.and_then(|id| self.git_repo.find_tree(id).ok());
+                    self.missing_objects(objects, &subtree, previous_subtree.as_ref(), missing)?;
+                }
+                Some(ObjectType::Blob) if !is_loose(entry.id()) => missing.push(entry.id()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Writes a commit without updating any ref, signed by the signer if there is one
+    fn create_commit(
+        &self,
+        signer: Option<&Signer>,
+        author: &Signature,
+        committer: &Signature,
+        message: &str,
+        tree: &Tree,
+        parents: &[&Commit],
+    ) -> Result<Oid, Error> {
+        let signer = match signer {
+            Some(signer) => signer,
+            None => {
+                return Ok(self
+                    .git_repo
+                    .commit(None, author, committer, message, tree, parents)?)
+            }
+        };
+        let buffer = self
+            .git_repo
+            .commit_create_buffer(author, committer, message, tree, parents)?;
+        let buffer = buffer
+            .as_str()
+            .ok_or_else(|| Error::Sign("commit is not valid UTF-8".into()))?;
+        let signature = signer.sign(buffer, committer)?;
+        Ok(self.git_repo.commit_signed(buffer, &signature, None)?)
+    }
+
+    // Writes a tree of the worktree using the snapshot ref's private index on a private repository
+    // handle, so the repository's own index (and anything staged in it) is never replaced or written
+    // Files larger than the filter's maximum size are skipped and returned with their sizes
+    fn worktree_tree(&self, filter: &PathFilter) -> Result<(Oid, Vec<(PathBuf, u64)>), Error> {
+        self.worktree_tree_in(&self.private_repo(false)?, filter, false)
+    }
+
+    // Opens another handle of the repository, writing objects only to memory if `in_memory`
+    fn private_repo(&self, in_memory: bool) -> Result<Repository, Error> {
+        let private_repo = Repository::open(self.git_repo.path())?;
+        if let Some(workdir) = self.git_repo.workdir() {
+            private_repo.set_workdir(workdir, false)?;
+        }
+        if in_memory {
+            // new objects go to the highest priority backend that can write them
+            private_repo
+                .odb()?
+                .add_new_mempack_backend(MEMPACK_PRIORITY)?;
+        }
+        Ok(private_repo)
+    }
+
+    // Path of the private index kept for a snapshot ref. A detached HEAD gets a snapshot ref
+    // for each commit it's on, e.g. every step of a rebase, so those share one index per worktree.
+    fn index_cache_path(&self, current_branch: &str, snapshot_ref_name: &str) -> PathBuf {
+        let dir = self.git_repo.path().join(INDEX_CACHE_DIR);
+        if current_branch == DETACHED_BRANCH {
+            dir.join(DETACHED_BRANCH)
+                .join(self.worktree_name().unwrap_or(MAIN_WORKTREE))
+        } else {
+            dir.join(snapshot_ref_name)
+        }
+    }
+
+    // Removes a private index along with its key file
+    fn remove_index_cache(&self, path: &Path) {
+        for path in [path.to_path_buf(), index_key_path(path)] {
+            if let Err(err) = remove_file(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!(target: self.name(), "unable to remove {}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+
+    // Removes the private indexes of snapshot refs that no longer exist
+    fn remove_stale_index_caches(&self) {
+        let dir = self.git_repo.path().join(INDEX_CACHE_DIR);
+        let mut dirs = vec![dir.join("refs")];
+        while let Some(current) = dirs.pop() {
+            let entries = match std::fs::read_dir(&current) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let ref_name = match path.strip_prefix(&dir).ok().and_then(Path::to_str) {
+                    Some(ref_name) if !ref_name.ends_with(".filter") => ref_name,
+                    _ => continue,
+                };
+                if self.git_repo.find_reference(ref_name).is_err() {
+                    debug!(target: self.name(), "removing snapshot index of {}", ref_name);
+                    self.remove_index_cache(&path);
+                }
+            }
+        }
+    }
+
+    // Opens the private index of the current snapshot ref. Its stat data from the previous
+    // snapshot lets unchanged files be skipped instead of rehashed, a new one starts from the
+    // repository's own index, which a dry run reads without copying. Next to it is the tree last
+    // written from it and the key of the filter and ignore rules it was written with. Unless
+    // that tree is still there, any of its blobs may be missing, e.g. garbage collected after
+    // dropping snapshots, and it starts over empty if one is. Returns the stored key, or
+    // `None` if its entries need to be checked against the filter again.
+    fn index_cache(
+        &self,
+        repo: &Repository,
+        dry_run: bool,
+    ) -> Result<(Index, Option<String>), Error> {
+        let (current_branch, snapshot_ref_name) =
+            self.snapshot_target(&self.project_repo().config()?)?;
+        let path = self.index_cache_path(&current_branch, &snapshot_ref_name);
+        let source = self.project_repo().path().join("index");
+        let opened = if path.exists() || !source.is_file() {
+            Index::open(&path).map_err(Error::from)
+        } else if dry_run {
+            Index::open(&source).map_err(Error::from)
+        } else {
+            Self::seed_index_cache(&source, &path).and_then(|_| Ok(Index::open(&path)?))
+        };
+        let mut index = match opened {
+            Ok(index) => index,
+            Err(err) => {
+                warn!(target: self.name(), "discarding snapshot index: {}", err);
+                let _ = std::fs::remove_file(&path);
+                Index::open(&path)?
+            }
+        };
+        // only the resolved side of a conflict has a file in the worktree, the rest is rehashed
+        if index.has_conflicts() {
+            let conflicts: Vec<(PathBuf, u16)> = index
+                .iter()
+                .map(|entry| (entry.path, (entry.flags >> INDEX_STAGE_SHIFT) & 0x3))
+                .filter(|(_, stage)| *stage != 0)
+                .filter_map(|(path, stage)| {
+                    Some((PathBuf::from(String::from_utf8(path).ok()?), stage))
+                })
+                .collect();
+            for (path, stage) in conflicts {
+                index.remove(&path, i32::from(stage))?;
+            }
+        }
+        let odb = repo.odb()?;
+        let stored = read_to_string(index_key_path(&path)).unwrap_or_default();
+        let key = stored
+            .split_once('\n')
+            .filter(|(tree, _)| Oid::from_str(tree).is_ok_and(|tree| odb.exists(tree)))
+            .map(|(_, key)| key.trim_end().to_owned());
+        if key.is_none() && index.iter().any(|entry| !odb.exists(entry.id)) {
+            debug!(target: self.name(), "snapshot index is out of date, rehashing worktree");
+            index.clear()?;
+        }
+        Ok((index, key))
+    }
+
+    // Copies an index to a new private index, keeping its modification time so entries racily
+    // clean in the original aren't taken as unchanged
+    fn seed_index_cache(source: &Path, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        std::fs::copy(source, path)?;
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(source.metadata()?.modified()?)?;
+        Ok(())
+    }
+
+    // Key of the filter and ignore rules deciding which worktree files are snapshotted, its
+    // .gitignore files are those in the worktree root and the index
+    fn index_key(&self, index: &Index, filter: &PathFilter) -> Result<String, Error> {
+        let project = self.project_repo();
+        let mut sources = vec![common_dir(project.path()).join("info/exclude")];
+        match project.config()?.get_path("core.excludesfile") {
+            Ok(path) => sources.push(path),
+            // git's default global ignore file
+            Err(_) => sources.extend(
+                std::env::var_os("XDG_CONFIG_HOME")
+                    .filter(|dir| !dir.is_empty())
+                    .map(PathBuf::from)
+                    .or_else(|| {
+                        std::env::var_os("HOME").map(|home| Path::new(&home).join(".config"))
+                    })
+                    .map(|dir| dir.join("git/ignore")),
+            ),
+        }
+        if let Some(workdir) = self.git_repo.workdir() {
+            sources.push(workdir.join(".gitignore"));
+            sources.extend(index.iter().filter_map(|entry| {
+                let path = std::str::from_utf8(&entry.path).ok()?;
+                path.ends_with("/.gitignore").then(|| workdir.join(path))
+            }));
+        }
+
+        let mut sha = Sha256::new();
+        sha.update(filter.key());
+        for source in sources {
+            sha.update(source.to_string_lossy().as_bytes());
+            sha.update(std::fs::read(&source).unwrap_or_default());
+        }
+        Ok(format!("{:x}", sha.finalize()))
+    }
+
+    // Writes the worktree tree into the given handle of the repository, LFS content is only
+    // hashed on a dry run, which also leaves the private index as it was
+    fn worktree_tree_in(
+        &self,
+        private_repo: &Repository,
+        filter: &PathFilter,
+        dry_run: bool,
+    ) -> Result<(Oid, Vec<(PathBuf, u64)>), Error> {
+        let (mut index, stored_key) = self.index_cache(private_repo, dry_run)?;
+        private_repo.set_index(&mut index)?;
+
+        // Ignored paths are only visited when they may be included
+        let force = filter.has_includes();
+        let workdir = private_repo.workdir().map(Path::to_path_buf);
+        let mut skipped = Vec::new();
+        let mut rejected = Vec::new();
+        let mut lfs_paths = Vec::new();
+        let mut callback = |path: &Path, _: &[u8]| {
+            // Nested repositories show up as directories, submodules are recorded below
+            if path.to_string_lossy().ends_with('/') {
+                return 1;
+            }
+            let ignored = force && private_repo.is_path_ignored(path).unwrap_or(false);
+            if !filter.is_included(path, ignored) {
+                rejected.push(path.to_path_buf());
+                return 1;
+            }
+            if let (true, Some(workdir)) = (filter.has_max_file_size(), &workdir) {
+                let size = workdir.join(path).symlink_metadata().map_or(0, |m| m.len());
+                if filter.is_oversized(size) {
+                    rejected.push(path.to_path_buf());
+                    skipped.push((path.to_path_buf(), size));
+                    return 1;
+                }
+            }
+            // LFS files are stored as pointers below
+            if is_lfs_path(private_repo, path) {
+                lfs_paths.push(path.to_path_buf());
+                return 1;
+            }
+            0
+        };
+        let flags = if force {
+            IndexAddOption::FORCE
+        } else {
+            IndexAddOption::DEFAULT
+        };
+        // Only files whose stat data differs from the private index are hashed
+        index.add_all(["*"], flags, Some(&mut callback))?;
+
+        // Copy changed LFS files to the local LFS store and record their pointers with the
+        // files' stat data, so they aren't read again until they change
+        if let Some(workdir) = &workdir {
+            let store = LfsStore::new(private_repo.path());
+            for path in &lfs_paths {
+                let file_path = workdir.join(path);
+                let metadata = match file_path.symlink_metadata() {
+                    Ok(metadata) => metadata,
+                    Err(_) => {
+                        index.remove_path(path)?;
+                        continue;
+                    }
+                };
+                let pointer = if dry_run {
+                    store.pointer(&file_path)?
+                } else {
+                    store.store(&file_path)?
+                };
+                let blob = private_repo.blob(&pointer.to_bytes())?;
+                index.add(&stat_index_entry(path, &metadata, blob))?;
+            }
+        }
+
+        // Drop entries of earlier snapshots that are left out now, e.g. files that grew too
+        // large. Unchanged files only become ignored or excluded with new rules, so the
+        // remaining entries are only checked when the rules changed.
+        let mut removed: Vec<PathBuf> = rejected
+            .into_iter()
+            .filter(|path| index.get_path(path, 0).is_some())
+            .collect();
+        let key = self.index_key(&index, filter)?;
+        if stored_key.as_ref() != Some(&key) {
+            debug!(target: self.name(), "checking snapshot index against the filter");
+            for entry in index.iter() {
+                let path = match std::str::from_utf8(&entry.path) {
+                    Ok(path) => Path::new(path),
+                    Err(_) => continue,
+                };
+                let ignored = private_repo.is_path_ignored(path).unwrap_or(false);
+                if !filter.is_included(path, ignored) {
+                    removed.push(path.to_path_buf());
+                }
+            }
+        }
+        for path in &removed {
+            index.remove_path(path)?;
+        }
+        let tree = index.write_tree()?;
+
+        if !dry_run {
+            // A snapshot taken at the same time may hold the lock, the next one catches up
+            let written = match index.path().map(Path::to_path_buf) {
+                Some(path) => path
+                    .parent()
+                    .map_or(Ok(()), create_dir_all)
+                    .map_err(Error::from)
+                    .and_then(|_| Ok(index.write()?))
+                    .and_then(|_| {
+                        let key_path = index_key_path(&path);
+                        Ok(std::fs::write(key_path, format!("{}\n{}\n", tree, key))?)
+                    }),
+                None => Ok(()),
+            };
+            if let Err(err) = written {
+                warn!(target: self.name(), "unable to write snapshot index: {:?}", err);
+            }
+        }
+
+        let mut updates = TreeUpdateBuilder::new();
+        let mut updated = false;
+
+        // Record checked out submodules at their current HEAD
+        for submodule in private_repo.submodules()? {
+            if let Some(id) = submodule.workdir_id() {
+                if filter.is_included(submodule.path(), false) {
+                    updates.upsert(submodule.path(), id, FileMode::Commit);
+                    updated = true;
+                }
+            }
+        }
+        if !updated {
+            return Ok((tree, skipped));
+        }
+        let tree = updates.create_updated(private_repo, &private_repo.find_tree(tree)?)?;
+        Ok((tree, skipped))
+    }
+
+    /// Returns the files left out of a snapshot for exceeding the maximum file size, with their sizes
+    pub fn snapshot_skipped(&self, snapshot: &Commit) -> Vec<(PathBuf, u64)> {
+        let message = snapshot.message().unwrap_or_default();
+        trailer_values(message, SKIPPED_TRAILER)
+            .filter_map(|value| {
+                let (size, path) = value.split_once(' ')?;
+                Some((PathBuf::from(path), size.parse().ok()?))
+            })
+            .collect()
+    }
+
+    // Writes the worktree tree with each dirty submodule snapshotted into its own snapshot ref,
+    // and recorded by that snapshot commit instead of its HEAD
+    fn snapshot_tree(
+        &self,
+        filter: &PathFilter,
+        options: &SnapshotOptions,
+    ) -> Result<(Oid, Vec<(PathBuf, u64)>), Error> {
+        let (tree, skipped) = self.worktree_tree(filter)?;
+
+        let mut updates = TreeUpdateBuilder::new();
+        let mut updated = false;
+        for submodule in self.project_repo().submodules()? {
+            let name = match submodule.name() {
+                Some(name) => name,
+                None => continue,
+            };
+            if !filter.is_included(submodule.path(), false) {
+                continue;
+            }
+            let status = self
+                .project_repo()
+                .submodule_status(name, SubmoduleIgnore::None)?;
+            if !status.intersects(
+                SubmoduleStatus::WD_INDEX_MODIFIED
+                    | SubmoduleStatus::WD_WD_MODIFIED
+                    | SubmoduleStatus::WD_UNTRACKED,
+            ) {
+                continue;
+            }
+
+            let submodule_repo = self.submodule_repo(&submodule)?;
+            if let Err(err) = submodule_repo.snapshot_with(options) {
+                error!(
+                    target: self.name(),
+                    "error snapshotting submodule {}: {:?}", name, err
+                );
+                continue;
+            }
+            let snapshot_id = submodule_repo.latest_snapshot()?.map(|c| c.id());
+            if let Some(snapshot_id) = snapshot_id {
+                updates.upsert(submodule.path(), snapshot_id, FileMode::Commit);
+                updated = true;
+            }
+        }
+
+        if !updated {
+            return Ok((tree, skipped));
+        }
+        let tree = updates.create_updated(&self.git_repo, &self.git_repo.find_tree(tree)?)?;
+        Ok((tree, skipped))
+    }
+
+    // Submodules are usually on a detached HEAD, so they are snapshotted for the parent's branch
+    fn submodule_repo(&self, submodule: &Submodule) -> Result<Repo, Error> {
+        let (current_branch, _) = self.snapshot_target(&self.project_repo().config()?)?;
+        let mut submodule_repo = Repo::open(submodule.open()?)?;
+        submodule_repo.branch = Some(current_branch);
+        Ok(submodule_repo)
+    }
+
+    // include and exclude patterns for the current branch
+    fn path_filter(&self) -> Result<PathFilter, Error> {
+        let config = self.project_repo().config()?;
+        let (current_branch, _) = self.snapshot_target(&config)?;
+        PathFilter::from_config(&config, &current_branch)
+    }
+
+    // Writes a tree of the repository's staging index, read from disk so it is left untouched.
+    // A conflicted index can't be written as one tree: the tree gets our side of each conflict
+    // and the common ancestor and their side, stages 1 and 3, are written to trees of their own.
+    fn index_tree(&self, repo: &Repository) -> Result<(Oid, Vec<Oid>), Error> {
+        let mut index = Index::open(&self.project_repo().path().join("index"))?;
+        if !index.has_conflicts() {
+            return Ok((index.write_tree_to(repo)?, Vec::new()));
+        }
+
+        let mut resolved = Index::new()?;
+        let mut conflicts = CONFLICT_TRAILERS
+            .iter()
+            .map(|_| Index::new())
+            .collect::<Result<Vec<_>, _>>()?;
+        for mut entry in index.iter() {
+            let stage = (entry.flags >> INDEX_STAGE_SHIFT) & 0x3;
+            entry.flags &= !(0x3 << INDEX_STAGE_SHIFT);
+            if stage == 0 || stage == 2 {
+                resolved.add(&entry)?;
+            } else if let Some(i) = CONFLICT_TRAILERS.iter().position(|(s, _)| *s == stage) {
+                conflicts[i].add(&entry)?;
+            }
+        }
+        let conflicts = conflicts
+            .iter_mut()
+            .map(|index| index.write_tree_to(repo))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((resolved.write_tree_to(repo)?, conflicts))
+    }
+
+    // Adds the conflicts recorded with an index commit back to the index, the resolved entry
+    // of each conflicted path becomes our side of the conflict
+    fn restore_conflicts(&self, index: &mut Index, index_commit: &Commit) -> Result<(), Error> {
+        let mut entries = Vec::new();
+        for (stage, key) in CONFLICT_TRAILERS {
+            let commit = match self.trailer_commit(index_commit, key)? {
+                Some(commit) => commit,
+                None => continue,
+            };
+            commit.tree()?.walk(TreeWalkMode::PreOrder, |dir, entry| {
+                if let (Some(ObjectType::Blob), Some(name)) = (entry.kind(), entry.name()) {
+                    entries.push(IndexEntry {
+                        ctime: IndexTime::new(0, 0),
+                        mtime: IndexTime::new(0, 0),
+                        dev: 0,
+                        ino: 0,
+                        mode: entry.filemode() as u32,
+                        uid: 0,
+                        gid: 0,
+                        file_size: 0,
+                        id: entry.id(),
+                        flags: stage << INDEX_STAGE_SHIFT,
+                        flags_extended: 0,
+                        path: format!("{}{}", dir, name).into_bytes(),
+                    });
+                }
+                TreeWalkResult::Ok
+            })?;
+        }
+
+        for entry in &entries {
+            let path = Path::new(std::str::from_utf8(&entry.path).unwrap_or_default());
+            if let Some(mut ours) = index.get_path(path, 0) {
+                index.remove(path, 0)?;
+                ours.flags |= 2 << INDEX_STAGE_SHIFT;
+                index.add(&ours)?;
+            }
+        }
+        for entry in &entries {
+            index.add(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the commit holding the staged tree of a snapshot, if it recorded one
+    pub fn snapshot_index(&self, snapshot: &Commit) -> Result<Option<Commit<'_>>, Error> {
+        self.trailer_commit(snapshot, INDEX_TRAILER)
+    }
+
+    /// Returns the branch commit a snapshot was taken on top of, if the branch had one
+    pub fn snapshot_base(&self, snapshot: &Commit) -> Result<Option<Commit<'_>>, Error> {
+        self.trailer_commit(snapshot, BASE_TRAILER)
+    }
+
+    fn trailer_commit(&self, snapshot: &Commit, key: &str) -> Result<Option<Commit<'_>>, Error> {
+        let id = snapshot
+            .message()
+            .and_then(|message| trailer(message, key))
+            .map(Oid::from_str)
+            .transpose()?;
+        match id.map(|id| self.git_repo.find_commit(id)).transpose() {
+            // a base commit a shadow repository only named may be gone from the project
+            Err(err) if err.code() == ErrorCode::NotFound && self.is_shadow() => Ok(None),
+            result => Ok(result?),
+        }
+    }
+
+    // remotes with snapshots enabled and the refspecs pushed to each of them
+    fn push_refspecs(
+        &self,
+        ref_name: &str,
+        current_branch: &str,
+        config: &Config,
+        force: bool,
+        notes: bool,
+    ) -> Result<Vec<(String, Vec<String>)>, Error> {
+        let remotes = self.project_repo().remotes()?;
+        let mut targets = Vec::new();
+
+        for (remote, name) in remotes.iter().zip(remotes.iter_bytes()) {
+            // reported as failed pushes by `push` if snapshots are enabled for them
+            let remote = match remote {
+                Some(remote) => remote,
+                None => {
+                    debug!(
+                        target: self.name(),
+                        "skipping remote with a non UTF-8 name: {}",
+                        String::from_utf8_lossy(name)
+                    );
+                    continue;
+                }
+            };
+
+            // Check remote config if snapshots are enabled, disabled by default
+            let enabled = bool::from_config(
+                config,
+                &[&format!("remote.{}.snapshotenabled", remote)],
+                false,
+            );
+
+            if !enabled {
+                debug!(
+                    target: self.name(),
+                    "snapshots disabled for remote: {}",
+                    remote
+                );
+                continue;
+            }
+
+            // Get remote snapshot branch from remote config, in the snapshot ref namespace,
+            // or default to the local snapshot ref
+            let snapshot_branch = String::from_config(
+                config,
+                &[&format!("remote.{}.snapshotbranch", remote)],
+                String::new(),
+            );
+            let snapshot_ref_name = if snapshot_branch.is_empty() {
+                ref_name.to_owned()
+            } else {
+                [Self::ref_namespace(config), snapshot_branch].concat()
+            };
+
+            let snapshot_ref_name = expand(
+                &snapshot_ref_name,
+                &[
+                    (BRANCH_SUB_KEY, current_branch),
+                    (
+                        WORKTREE_SUB_KEY,
+                        self.worktree_name().unwrap_or(MAIN_WORKTREE),
+                    ),
+                ],
+            );
+
+            // Rewritten snapshot history needs a forced push
+            let mut refspecs = vec![format!(
+                "{}{}:{}",
+                if force { "+" } else { "" },
+                ref_name,
+                snapshot_ref_name
+            )];
+            // Snapshot notes of this machine are pushed along with the snapshots
+            if notes {
+                let notes_ref = Self::notes_ref(config);
+                refspecs.push(format!("{}:{}", notes_ref, notes_ref));
+            }
+            targets.push((remote.to_owned(), refspecs));
+        }
+        Ok(targets)
+    }
+
+    fn push(
+        &self,
+        ref_name: &str,
+        current_branch: &str,
+        config: &Config,
+        force: bool,
+    ) -> Result<Vec<PushResult>, Error> {
+        let notes = self
+            .git_repo
+            .find_reference(&Self::notes_ref(config))
+            .is_ok();
+        let mut results = Vec::new();
+        for (remote, refspecs) in
+            self.push_refspecs(ref_name, current_branch, config, force, notes)?
+        {
+            let result = self.push_remote(&remote, &refspecs, config);
+            if let Err(err) = &result {
+                error!(
+                    target: self.name(),
+                    "error pushing snapshot branch to remote {}: {}",
+                    remote,
+                    err
+                );
+            } else {
+                info!(
+                    target: self.name(),
+                    "pushed snapshot branch to remote: {}",
+                    remote
+                );
+            }
+            results.push(PushResult {
+                remote,
+                error: result.err(),
+            });
+        }
+        for remote in self.non_utf8_remotes(config)? {
+            error!(
+                target: self.name(),
+                "unable to push snapshot branch to remote with a non UTF-8 name: {}", remote
+            );
+            results.push(PushResult {
+                remote,
+                error: Some(PushError::Other("remote name is not valid UTF-8".into())),
+            });
+        }
+        Ok(results)
+    }
+
+    // Lossy names of the remotes with snapshots enabled whose names aren't valid UTF-8, which
+    // can't be pushed to
+    fn non_utf8_remotes(&self, config: &Config) -> Result<Vec<String>, Error> {
+        let remotes = self.project_repo().remotes()?;
+        let mut names = Vec::new();
+        for (remote, name) in remotes.iter().zip(remotes.iter_bytes()) {
+            if remote.is_some() {
+                continue;
+            }
+            let key = [b"remote.", name, b".snapshotenabled"].concat();
+            let mut enabled = false;
+            for entry in &config.entries(Some("snapshotenabled"))? {
+                let entry = entry?;
+                if entry.name_bytes() == key.as_slice() {
+                    // a key without a value is true, the last one wins
+                    enabled = entry
+                        .value()
+                        .is_none_or(|value| Config::parse_bool(value).unwrap_or(false));
+                }
+            }
+            if enabled {
+                names.push(String::from_utf8_lossy(name).into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn push_remote(
+        &self,
+        remote: &str,
+        refspecs: &[String],
+        config: &Config,
+    ) -> Result<(), PushError> {
+        let mut remote = self.git_repo.find_remote(remote)?;
+        let mut rejected = Vec::new();
+
+        let mut callbacks = RemoteCallbacks::new();
+
+        // Only allow non-interactive credentials
+        // TODO: Look into using default ssh key
+        callbacks.credentials(move |url, username, allowed_types| {
+            if allowed_types.is_user_pass_plaintext() {
+                if let Ok(cred) = Cred::credential_helper(config, url, username) {
+                    return Ok(cred);
+                }
+            }
+            if allowed_types.is_ssh_key() {
+                if let Some(username) = username {
+                    if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                        return Ok(cred);
+                    }
+                }
+            }
+            Err(git2::Error::new(
+                git2::ErrorCode::Auth,
+                git2::ErrorClass::Callback,
+                "unable to authenticate, setup ssh key agent or credential helper for this remote and username",
+            ))
+        });
+
+        // The server reports rejected refs, e.g. non-fast-forward updates, per ref
+        callbacks.push_update_reference(|reference, status| {
+            if let Some(status) = status {
+                rejected.push(format!("{} ({})", reference, status));
+            }
+            Ok(())
+        });
+
+        let mut opts = PushOptions::new();
+        opts.remote_callbacks(callbacks);
+        remote.push(refspecs, Some(&mut opts))?;
+        drop(opts);
+
+        if !rejected.is_empty() {
+            return Err(PushError::Rejected(rejected.join(", ")));
+        }
+        Ok(())
+    }
+
+    /// Returns the latest snapshot of the current branch, if one exists
+    pub fn latest_snapshot(&self) -> Result<Option<Commit<'_>>, Error> {
+        let config = self.project_repo().config()?;
+        let (_, snapshot_ref_name) = self.snapshot_target(&config)?;
+        match self.git_repo.find_reference(&snapshot_ref_name) {
+            Ok(reference) => Ok(Some(reference.peel_to_commit()?)),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Iterates the snapshots of the current branch, newest first
+    pub fn snapshots(&self) -> Result<Snapshots<'_>, Error> {
+        Ok(Snapshots {
+            repo: self,
+            next: self.latest_snapshot()?,
+        })
+    }
+
+    fn snapshot_chain(&self, tip: &Commit) -> Snapshots<'_> {
+        Snapshots {
+            repo: self,
+            next: self.git_repo.find_commit(tip.id()).ok(),
+        }
+    }
+
+    /// Returns the snapshot a snapshot was taken after, skipping the index and base parents
+    pub fn previous_snapshot(&self, snapshot: &Commit) -> Result<Option<Commit<'_>>, Error> {
+        let parent_id = match snapshot.parent_ids().next() {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let index = self.snapshot_index(snapshot)?.map(|c| c.id());
+        let base = self.snapshot_base(snapshot)?.map(|c| c.id());
+        if index == Some(parent_id) || base == Some(parent_id) {
+            return Ok(None);
+        }
+        Ok(Some(self.git_repo.find_commit(parent_id)?))
+    }
+
+    /// Diff stats of a snapshot relative to the previous snapshot, or to the branch commit it
+    /// was taken on for the first snapshot
+    pub fn snapshot_stats(&self, snapshot: &Commit) -> Result<DiffStats, Error> {
+        let previous = match self.previous_snapshot(snapshot)? {
+            Some(previous) => Some(previous),
+            None => self.snapshot_base(snapshot)?,
+        };
+        let previous_tree = match previous {
+            Some(previous) => Some(previous.tree()?),
+            None => None,
+        };
+        let diff = self.git_repo.diff_tree_to_tree(
+            previous_tree.as_ref(),
+            Some(&snapshot.tree()?),
+            None,
+        )?;
+        Ok(diff.stats()?)
+    }
+
+    /// Resolves a snapshot from a revision, defaulting to the latest snapshot of the current branch
+    pub fn find_snapshot(&self, rev: Option<&str>) -> Result<Commit<'_>, Error> {
+        match rev {
+            Some(rev) => Ok(self.git_repo.revparse_single(rev)?.peel_to_commit()?),
+            None => self.latest_snapshot()?.ok_or(Error::SnapshotNotFound),
+        }
+    }
+
+    /// Returns the latest snapshot of the current branch taken before the given time
+    pub fn find_snapshot_before(&self, time: SystemTime) -> Result<Commit<'_>, Error> {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        for snapshot in self.snapshots()? {
+            let snapshot = snapshot?;
+            if snapshot.time().seconds() <= seconds {
+                return Ok(snapshot);
+            }
+        }
+        Err(Error::SnapshotNotFound)
+    }
+
+    /// Diffs a snapshot to another snapshot, or to the current worktree if none is given
+    pub fn diff_snapshot(
+        &self,
+        snapshot: &Commit,
+        other: Option<&Commit>,
+    ) -> Result<Diff<'_>, Error> {
+        let new_tree = match other {
+            Some(other) => other.tree()?,
+            None => self
+                .git_repo
+                .find_tree(self.worktree_tree(&self.path_filter()?)?.0)?,
+        };
+        Ok(self
+            .git_repo
+            .diff_tree_to_tree(Some(&snapshot.tree()?), Some(&new_tree), None)?)
+    }
+
+    /// Checks out a snapshot into the worktree and restores its staged index, returning the
+    /// changed paths. A safety snapshot of the current state is taken first, if the current
+    /// changes can't be saved the restore is refused unless `force` is set.
+    pub fn restore(&self, snapshot: &Commit, force: bool) -> Result<Vec<PathBuf>, Error> {
+        let safety_snapshot = match self.snapshot() {
+            Ok(outcome) => outcome.commit,
+            // the safety snapshot is saved even if pushing it failed
+            Err(Error::Push(outcome)) => outcome.commit,
+            Err(err) if !force => return Err(err),
+            Err(err) => {
+                warn!(target: self.name(), "unable to take safety snapshot: {:?}", err);
+                None
+            }
+        };
+
+        // The safety snapshot holds the current state, without one it's written again
+        let filter = self.path_filter()?;
+        let current_tree = match safety_snapshot {
+            Some(id) => self.git_repo.find_commit(id)?.tree()?,
+            None => self
+                .git_repo
+                .find_tree(self.snapshot_tree(&filter, &SnapshotOptions::default())?.0)?,
+        };
+
+        let saved = self
+            .latest_snapshot()
+            .ok()
+            .flatten()
+            .is_some_and(|c| c.tree_id() == current_tree.id());
+        if !saved && !force {
+            return Err(Error::UnsavedChanges);
+        }
+
+        let snapshot_tree = snapshot.tree()?;
+        let diff =
+            self.git_repo
+                .diff_tree_to_tree(Some(&current_tree), Some(&snapshot_tree), None)?;
+        // excluded paths are left as they are, even if an older snapshot still has them
+        let mut changed: Vec<PathBuf> = diff
+            .deltas()
+            .filter_map(|d| d.new_file().path().or_else(|| d.old_file().path()))
+            .filter(|path| filter.is_included(path, false))
+            .map(Path::to_path_buf)
+            .collect();
+
+        // Only paths the current snapshot tree has are deleted, files left out of snapshots
+        // stay in the worktree
+        let (present, deleted): (Vec<&PathBuf>, Vec<&PathBuf>) = changed
+            .iter()
+            .partition(|path| snapshot_tree.get_path(path).is_ok());
+        if let Some(workdir) = self.git_repo.workdir() {
+            for path in deleted {
+                let path = workdir.join(path);
+                if path.symlink_metadata().is_ok_and(|m| !m.is_dir()) {
+                    remove_file(&path)?;
+                }
+                // drop directories left empty, like checkout does
+                for dir in path.ancestors().skip(1).take_while(|dir| *dir != workdir) {
+                    if remove_dir(dir).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Overwrite the remaining changed paths with the snapshot, the index is restored
+        // separately below
+        if !present.is_empty() {
+            let mut checkout = CheckoutBuilder::new();
+            checkout.force().update_index(false);
+            for path in present {
+                checkout.path(path);
+            }
+            self.git_repo
+                .checkout_tree(snapshot_tree.as_object(), Some(&mut checkout))?;
+        }
+
+        // Checkout writes LFS pointers, replace them with the locally stored content
+        if let Some(workdir) = self.git_repo.workdir() {
+            let store = LfsStore::new(self.git_repo.path());
+            for path in &changed {
+                if is_lfs_path(&self.git_repo, path) && workdir.join(path).is_file() {
+                    store.smudge(&workdir.join(path))?;
+                }
+            }
+        }
+
+        // Restore submodules to the commits recorded for them in the snapshot, those recorded
+        // at the same commit as now are left alone
+        let changed_paths = changed.clone();
+        for submodule in self.project_repo().submodules()? {
+            if !changed_paths.iter().any(|path| path == submodule.path()) {
+                continue;
+            }
+            let id = match snapshot_tree.get_path(submodule.path()) {
+                Ok(entry) if entry.kind() == Some(ObjectType::Commit) => entry.id(),
+                _ => continue,
+            };
+            let submodule_repo = match self.submodule_repo(&submodule) {
+                Ok(submodule_repo) => submodule_repo,
+                Err(_) => continue,
+            };
+            let submodule_snapshot = match submodule_repo.git_repo.find_commit(id) {
+                Ok(commit) => commit,
+                Err(_) => continue,
+            };
+            let submodule_changed = submodule_repo.restore(&submodule_snapshot, force)?;
+            changed.extend(
+                submodule_changed
+                    .into_iter()
+                    .map(|path| submodule.path().join(path)),
+            );
+        }
+
+        let index_commit = self.snapshot_index(snapshot)?;
+        let index_tree = match &index_commit {
+            Some(index_commit) => index_commit.tree()?,
+            None => snapshot_tree,
+        };
+        let mut index = self.project_repo().index()?;
+        index.read(true)?;
+        index.read_tree(&index_tree)?;
+        if let Some(index_commit) = &index_commit {
+            self.restore_conflicts(&mut index, index_commit)?;
+        }
+        index.write()?;
+
+        info!(
+            target: self.name(),
+            "restored snapshot: {}",
+            snapshot.id()
+        );
+
+        Ok(changed)
+    }
+
+    /// Thins the snapshot history of the current branch with the configured retention policy,
+    /// rewriting the snapshot chain to the surviving snapshots. The previous chain is kept in a
+    /// backup ref until the next prune. With `push` the rewritten chain is force pushed to the
+    /// remotes with snapshots enabled. Returns the number of pruned snapshots.
+    pub fn prune(&self, push: bool) -> Result<usize, Error> {
+        self.prune_at(SystemTime::now(), push)
+    }
+
+    fn prune_at(&self, now: SystemTime, push: bool) -> Result<usize, Error> {
+        let config = self.project_repo().config()?;
+        let (current_branch, snapshot_ref_name) = self.snapshot_target(&config)?;
+
+        let policy = RetentionPolicy::from_config(&config, &current_branch);
+        if !policy.is_enabled() {
+            info!(target: self.name(), "No retention policy configured, aborting prune");
+            return Ok(0);
+        }
+
+        let snapshots = self.snapshots()?.collect::<Result<Vec<_>, _>>()?;
+        let tip = match snapshots.first() {
+            Some(tip) => tip.id(),
+            None => return Ok(0),
+        };
+
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let times: Vec<i64> = snapshots.iter().map(|c| c.time().seconds()).collect();
+        let keep = policy.retain(now, &times);
+        let pruned = keep.iter().filter(|&&keep| !keep).count();
+        if pruned == 0 {
+            info!(target: self.name(), "No snapshots to prune");
+            return Ok(0);
+        }
+
+        // Rewritten snapshots are signed again since their content changes
+        let signer = Signer::from_config(&config, &current_branch);
+        let notes_refs = self.notes_refs(&config)?;
+
+        // Recreate the surviving snapshots oldest first, chaining each to the previous survivor
+        // while keeping their index and base parents
+        let mut new_tip = None;
+        for (snapshot, _) in snapshots.iter().zip(keep).rev().filter(|(_, keep)| *keep) {
+            let skip = usize::from(self.previous_snapshot(snapshot)?.is_some());
+            let parents = new_tip
+                .map(|id| self.git_repo.find_commit(id))
+                .into_iter()
+                .chain(snapshot.parents().skip(skip).map(Ok))
+                .collect::<Result<Vec<_>, _>>()?;
+            let parents: Vec<&Commit> = parents.iter().collect();
+            let id = self.create_commit(
+                signer.as_ref(),
+                &snapshot.author(),
+                &snapshot.committer(),
+                &String::from_utf8_lossy(snapshot.message_bytes()),
+                &snapshot.tree()?,
+                &parents,
+            )?;
+            // Carry the metadata notes over to the recreated snapshot
+            for notes_ref in &notes_refs {
+                if let Ok(note) = self.git_repo.find_note(Some(notes_ref), snapshot.id()) {
+                    self.git_repo.note(
+                        &note.author(),
+                        &note.committer(),
+                        Some(notes_ref),
+                        id,
+                        &String::from_utf8_lossy(note.message_bytes()),
+                        true,
+                    )?;
+                }
+            }
+            new_tip = Some(id);
+        }
+        let new_tip = new_tip.ok_or(Error::SnapshotNotFound)?;
+
+        // Only move the snapshot ref if no snapshot was taken in the meantime
+        self.git_repo.reference_matching(
+            &snapshot_ref_name,
+            new_tip,
+            true,
+            tip,
+            "snapshot: prune",
+        )?;
+        self.git_repo.reference(
+            &Self::backup_ref_name(&snapshot_ref_name),
+            tip,
+            true,
+            "snapshot: prune backup",
+        )?;
+        // The rewritten chain starts over with a fresh private index
+        self.remove_index_cache(&self.index_cache_path(&current_branch, &snapshot_ref_name));
+        self.remove_stale_index_caches();
+
+        info!(
+            target: self.name(),
+            "pruned {} snapshots of branch: {}", pruned, current_branch
+        );
+
+        // Rewritten history replaces the remote chain, so it's only pushed when asked for
+        if push {
+            let pushes = self.push(&snapshot_ref_name, &current_branch, &config, true)?;
+            if PushFailurePolicy::from_config(&config, &current_branch).fails(&pushes) {
+                return Err(Error::Push(Box::new(SnapshotOutcome {
+                    commit: Some(new_tip),
+                    ref_name: snapshot_ref_name,
+                    pushes,
+                    ..Default::default()
+                })));
+            }
+        }
+        Ok(pruned)
+    }
+
+    // backup ref holding the snapshot chain before the last prune, e.g. refs/snapshot-backup/heads/snapshot/main
+    fn backup_ref_name(snapshot_ref_name: &str) -> String {
+        [
+            BACKUP_REF_PREFIX,
+            snapshot_ref_name.trim_start_matches("refs/"),
+        ]
+        .concat()
+    }
+
+    pub fn current_branch(&self) -> Result<String, Error> {
+        if let Some(branch) = &self.branch {
+            return Ok(branch.clone());
+        }
+        match self.project_repo().head() {
+            Ok(reference) => {
+                if !reference.is_branch() || reference.is_remote() {
+                    return Err(Error::InvalidHead);
+                }
+                reference
+                    .shorthand()
+                    .map(|r| r.to_owned())
+                    .ok_or(Error::InvalidHead)
+            }
+            Err(err) => {
+                if err.code() == ErrorCode::UnbornBranch {
+                    let reference = self.project_repo().find_reference("HEAD")?;
+                    let target = reference.symbolic_target().ok_or(Error::InvalidHead)?;
+                    return Ok(branch_ref_shorthand(target).to_owned());
+                }
+                Err(Error::InvalidHead)
+            }
+        }
+    }
+
+    /// Whether a worktree path is left out of snapshots, by .gitignore or the snapshot include
+    /// and exclude patterns
+    pub fn is_ignored(&self, path: &Path) -> Result<bool, Error> {
+        let ignored = self.project_repo().is_path_ignored(path)?;
+        Ok(!self.path_filter()?.is_included(path, ignored))
+    }
+}
+
+/// Iterator over a snapshot branch, following each snapshot to the previous one
+pub struct Snapshots<'r> {
+    repo: &'r Repo,
+    next: Option<Commit<'r>>,
+}
+
+impl<'r> Iterator for Snapshots<'r> {
+    type Item = Result<Commit<'r>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let snapshot = self.next.take()?;
+        match self.repo.previous_snapshot(&snapshot) {
+            Ok(previous) => {
+                self.next = previous;
+                Some(Ok(snapshot))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
     use std::path::Path;
 
-    use git2::Signature;
-    use tempfile::{tempdir, NamedTempFile};
+    use std::time::Duration;
+    use tempfile::{tempdir, NamedTempFile};
+
+    use super::*;
+
+    use crate::lfs::LfsPointer;
+    use crate::sign::tests::{stub_signer, STUB_SIGNATURE};
+    use crate::util::tests::*;
+
+    const TEST_REMOTE_NAME: &str = "test";
+
+    fn test_repo_with_files(path: &Path) -> (Repository, Config) {
+        let (repo, config) = test_repo(path);
+        NamedTempFile::new_in(path).unwrap().keep().unwrap();
+        (repo, config)
+    }
+
+    fn test_repo_with_remote(path: &Path, remote_path: &Path) -> (Repository, Repository, Config) {
+        let (repo, config) = test_repo_with_files(path);
+        let remote_repo = Repository::init_bare(remote_path).unwrap();
+        repo.remote(
+            TEST_REMOTE_NAME,
+            &format!("file://{}", remote_repo.path().to_str().unwrap()),
+        )
+        .unwrap();
+        repo.config()
+            .unwrap()
+            .set_bool(&format!("remote.{}.snapshotenabled", "test"), true)
+            .unwrap();
+        (repo, remote_repo, config)
+    }
+
+    fn commit_all(repo: &Repository) {
+        let mut index = Index::new().unwrap();
+        repo.set_index(&mut index).unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        let tree = index.write_tree().unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+
+        let signature = Signature::now("test", "test").unwrap();
+
+        repo.commit(Some("HEAD"), &signature, &signature, "", &tree, &[])
+            .unwrap();
+    }
+
+    pub fn check_snapshot_exists(repo: &Repo) -> bool {
+        let config = repo.project_repo().config().unwrap();
+        let snapshot_branch = Repo::snapshot_branch(
+            &config,
+            &repo.current_branch().unwrap(),
+            repo.worktree_name(),
+        );
+        repo.git_repo
+            .resolve_reference_from_short_name(&snapshot_branch)
+            .is_ok()
+    }
+
+    pub fn snapshot_commit(repo: &Repo) -> Commit<'_> {
+        let config = repo.project_repo().config().unwrap();
+        let snapshot_branch = Repo::snapshot_branch(
+            &config,
+            &repo.current_branch().unwrap(),
+            repo.worktree_name(),
+        );
+        repo.git_repo
+            .resolve_reference_from_short_name(&snapshot_branch)
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+    }
+
+    #[test]
+    fn snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        commit_all(&repo);
+
+        NamedTempFile::new_in(temp_dir.path())
+            .unwrap()
+            .keep()
+            .unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+    }
+
+    #[test]
+    fn snapshot_preserves_index() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        commit_all(&repo);
+        let repo = Repository::open(temp_dir.path()).unwrap();
+
+        // Stage one new file and leave another unstaged
+        std::fs::write(temp_dir.path().join("staged"), "staged").unwrap();
+        std::fs::write(temp_dir.path().join("unstaged"), "unstaged").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("staged")).unwrap();
+        index.write().unwrap();
+
+        let index_path = repo.path().join("index");
+        let before = std::fs::read(&index_path).unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let after = std::fs::read(&index_path).unwrap();
+        assert_eq!(before, after);
+
+        // The repo handle still uses the on-disk index
+        let index = repo.git_repo().index().unwrap();
+        assert_eq!(Some(index_path.as_path()), index.path());
+        assert!(index.get_path(Path::new("staged"), 0).is_some());
+        assert!(index.get_path(Path::new("unstaged"), 0).is_none());
+
+        // The snapshot still captures unstaged files
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        assert!(tree.get_name("staged").is_some());
+        assert!(tree.get_name("unstaged").is_some());
+    }
+
+    #[test]
+    fn snapshot_index_tree() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+
+        std::fs::write(temp_dir.path().join("file"), "staged").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        index.write().unwrap();
+        std::fs::write(temp_dir.path().join("file"), "edited").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        let index_commit = repo.snapshot_index(&snapshot).unwrap().unwrap();
+        assert!(snapshot.parent_ids().any(|id| id == index_commit.id()));
+
+        let blob_content = |tree: git2::Tree| {
+            let entry = tree.get_name("file").unwrap();
+            let blob = repo.git_repo().find_blob(entry.id()).unwrap();
+            blob.content().to_vec()
+        };
+        assert_eq!(b"edited".to_vec(), blob_content(snapshot.tree().unwrap()));
+        assert_eq!(
+            b"staged".to_vec(),
+            blob_content(index_commit.tree().unwrap())
+        );
+    }
+
+    #[test]
+    fn snapshot_index_only_change() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        std::fs::write(temp_dir.path().join("file"), "content").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let first_commit = snapshot_commit(&repo);
+
+        let mut index = repo.git_repo().index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        index.write().unwrap();
+
+        repo.snapshot().unwrap();
+        let second_commit = snapshot_commit(&repo);
+
+        assert_ne!(first_commit.id(), second_commit.id());
+        assert_eq!(first_commit.tree_id(), second_commit.tree_id());
+        assert_eq!(Some(first_commit.id()), second_commit.parent_ids().next());
+    }
+
+    #[test]
+    fn snapshot_base() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        commit_all(&repo);
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        create_temp_file(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        let base = repo.snapshot_base(&snapshot).unwrap().unwrap();
+        assert_eq!(head, base.id());
+        assert!(snapshot.parent_ids().any(|id| id == head));
+
+        let index_commit = repo.snapshot_index(&snapshot).unwrap().unwrap();
+        assert_eq!(Some(head), index_commit.parent_ids().next());
+    }
+
+    #[test]
+    fn snapshot_base_unborn_branch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        assert!(repo.snapshot_base(&snapshot).unwrap().is_none());
+    }
+
+    #[test]
+    fn restore() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join("a"), "one").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let first = snapshot_commit(&repo);
+
+        std::fs::write(path.join("a"), "two").unwrap();
+        std::fs::write(path.join("b"), "new").unwrap();
+
+        let mut changed = repo.restore(&first, false).unwrap();
+        changed.sort();
+        assert_eq!(vec![PathBuf::from("a"), PathBuf::from("b")], changed);
+
+        assert_eq!("one", std::fs::read_to_string(path.join("a")).unwrap());
+        assert!(!path.join("b").exists());
+
+        // The state before the restore was saved in a safety snapshot
+        let safety = snapshot_commit(&repo);
+        assert_eq!(Some(first.id()), safety.parent_ids().next());
+        assert!(safety.tree().unwrap().get_name("b").is_some());
+    }
+
+    #[test]
+    fn restore_excluded() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join("file"), "one").unwrap();
+        std::fs::write(path.join("old.bin"), "old").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let first = snapshot_commit(&repo);
+
+        config.set_str("snapshot.exclude", "*.bin").unwrap();
+        std::fs::write(path.join("file"), "two").unwrap();
+        std::fs::write(path.join("old.bin"), "changed").unwrap();
+        std::fs::write(path.join("keep.bin"), "keep").unwrap();
+
+        let changed = repo.restore(&first, false).unwrap();
+        assert_eq!(vec![PathBuf::from("file")], changed);
+        assert_eq!("one", std::fs::read_to_string(path.join("file")).unwrap());
+
+        // Excluded files are neither deleted nor overwritten
+        assert_eq!(
+            "keep",
+            std::fs::read_to_string(path.join("keep.bin")).unwrap()
+        );
+        assert_eq!(
+            "changed",
+            std::fs::read_to_string(path.join("old.bin")).unwrap()
+        );
+    }
+
+    #[test]
+    fn restore_index() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        std::fs::write(temp_dir.path().join("file"), "content").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        index.write().unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let snapshot = snapshot_commit(&repo);
+
+        let mut index = repo.git_repo().index().unwrap();
+        index.clear().unwrap();
+        index.write().unwrap();
+
+        repo.restore(&snapshot, false).unwrap();
+
+        let mut index = repo.git_repo().index().unwrap();
+        index.read(true).unwrap();
+        assert!(index.get_path(Path::new("file"), 0).is_some());
+    }
+
+    #[test]
+    fn restore_unsaved_changes() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        std::fs::write(temp_dir.path().join("file"), "one").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let snapshot = snapshot_commit(&repo);
+
+        // Disable snapshots so the safety snapshot can't save the change
+        let current_branch = repo.current_branch().unwrap();
+        config
+            .set_bool(&format!("branch.{}.snapshotenabled", current_branch), false)
+            .unwrap();
+        std::fs::write(temp_dir.path().join("file"), "two").unwrap();
+
+        assert!(matches!(
+            repo.restore(&snapshot, false).err().unwrap(),
+            Error::UnsavedChanges
+        ));
+        assert_eq!(
+            "two",
+            std::fs::read_to_string(temp_dir.path().join("file")).unwrap()
+        );
+
+        repo.restore(&snapshot, true).unwrap();
+        assert_eq!(
+            "one",
+            std::fs::read_to_string(temp_dir.path().join("file")).unwrap()
+        );
+    }
+
+    #[test]
+    fn find_snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        assert!(matches!(
+            repo.find_snapshot(None).err().unwrap(),
+            Error::SnapshotNotFound
+        ));
+
+        repo.snapshot().unwrap();
+        let snapshot = snapshot_commit(&repo);
+        assert_eq!(snapshot.id(), repo.find_snapshot(None).unwrap().id());
+        assert_eq!(
+            snapshot.id(),
+            repo.find_snapshot(Some(&snapshot.id().to_string()))
+                .unwrap()
+                .id()
+        );
+    }
+
+    #[test]
+    fn snapshots() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+
+        std::fs::write(path.join("file"), "one\n").unwrap();
+        commit_all(&repo);
+
+        let repo = Repo::new(repo);
+        std::fs::write(path.join("file"), "one\ntwo\n").unwrap();
+        repo.snapshot().unwrap();
+        std::fs::write(path.join("file"), "three\n").unwrap();
+        std::fs::write(path.join("other"), "other\n").unwrap();
+        repo.snapshot().unwrap();
+
+        let snapshots: Vec<Commit> = repo.snapshots().unwrap().map(Result::unwrap).collect();
+        assert_eq!(2, snapshots.len());
+        assert_eq!(snapshot_commit(&repo).id(), snapshots[0].id());
+
+        let stats = repo.snapshot_stats(&snapshots[0]).unwrap();
+        assert_eq!(2, stats.files_changed());
+        assert_eq!(2, stats.insertions());
+        assert_eq!(2, stats.deletions());
+
+        // The first snapshot has no previous snapshot and diffs against its base commit
+        let stats = repo.snapshot_stats(&snapshots[1]).unwrap();
+        assert_eq!(1, stats.files_changed());
+        assert_eq!(1, stats.insertions());
+        assert_eq!(0, stats.deletions());
+    }
+
+    #[test]
+    fn snapshot_stats_unborn_branch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        std::fs::write(temp_dir.path().join("file"), "one\ntwo\n").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        // Without a base commit the first snapshot diffs against an empty tree
+        let stats = repo.snapshot_stats(&snapshot_commit(&repo)).unwrap();
+        assert_eq!(1, stats.files_changed());
+        assert_eq!(2, stats.insertions());
+    }
+
+    #[test]
+    fn snapshots_empty() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        assert_eq!(0, repo.snapshots().unwrap().count());
+    }
+
+    #[test]
+    fn diff_snapshot() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join("a"), "one").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let first = snapshot_commit(&repo);
+
+        std::fs::write(path.join("b"), "two").unwrap();
+
+        let diff = repo.diff_snapshot(&first, None).unwrap();
+        let paths: Vec<&Path> = diff.deltas().filter_map(|d| d.new_file().path()).collect();
+        assert_eq!(vec![Path::new("b")], paths);
+
+        repo.snapshot().unwrap();
+        let second = snapshot_commit(&repo);
+        assert_eq!(
+            1,
+            repo.diff_snapshot(&first, Some(&second))
+                .unwrap()
+                .deltas()
+                .len()
+        );
+        assert_eq!(0, repo.diff_snapshot(&second, None).unwrap().deltas().len());
+    }
+
+    #[test]
+    fn find_snapshot_before() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let snapshot = snapshot_commit(&repo);
+
+        assert_eq!(
+            snapshot.id(),
+            repo.find_snapshot_before(SystemTime::now()).unwrap().id()
+        );
+        assert!(matches!(
+            repo.find_snapshot_before(UNIX_EPOCH).err().unwrap(),
+            Error::SnapshotNotFound
+        ));
+    }
+
+    #[test]
+    fn prune() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config.set_str("snapshot.retainall", "1day").unwrap();
+
+        let repo = Repo::new(repo);
+        for content in ["one", "two", "three"] {
+            std::fs::write(temp_dir.path().join("file"), content).unwrap();
+            repo.snapshot().unwrap();
+        }
+        let tip = snapshot_commit(&repo);
+        assert_eq!(3, repo.snapshots().unwrap().count());
+
+        let later = SystemTime::now() + Duration::from_secs(14 * 24 * 60 * 60);
+        assert_eq!(2, repo.prune_at(later, true).unwrap());
+
+        let snapshots: Vec<Commit> = repo.snapshots().unwrap().map(Result::unwrap).collect();
+        assert_eq!(1, snapshots.len());
+        let new_tip = &snapshots[0];
+        assert_ne!(tip.id(), new_tip.id());
+        assert_eq!(tip.tree_id(), new_tip.tree_id());
+        assert_eq!(tip.message(), new_tip.message());
+        assert_eq!(
+            repo.snapshot_index(&tip).unwrap().unwrap().id(),
+            repo.snapshot_index(new_tip).unwrap().unwrap().id()
+        );
+
+        // The old chain is kept in the backup ref
+        let snapshot_ref_name =
+            Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), None);
+        let backup = repo
+            .git_repo()
+            .find_reference(&Repo::backup_ref_name(&snapshot_ref_name))
+            .unwrap();
+        assert_eq!(Some(tip.id()), backup.target());
+
+        // The rewritten history is force pushed
+        let remote_ref = remote_repo.find_reference(&snapshot_ref_name).unwrap();
+        assert_eq!(Some(new_tip.id()), remote_ref.target());
+    }
+
+    #[test]
+    fn prune_without_push() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config.set_str("snapshot.retainall", "1day").unwrap();
+
+        let repo = Repo::new(repo);
+        for content in ["one", "two"] {
+            std::fs::write(temp_dir.path().join("file"), content).unwrap();
+            repo.snapshot().unwrap();
+        }
+        let tip = snapshot_commit(&repo);
+
+        let later = SystemTime::now() + Duration::from_secs(14 * 24 * 60 * 60);
+        assert_eq!(1, repo.prune_at(later, false).unwrap());
+        assert_ne!(tip.id(), snapshot_commit(&repo).id());
+
+        // The remote keeps the previous chain
+        let snapshot_ref_name =
+            Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), None);
+        let remote_ref = remote_repo.find_reference(&snapshot_ref_name).unwrap();
+        assert_eq!(Some(tip.id()), remote_ref.target());
+    }
+
+    #[test]
+    fn prune_index_cache() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_str("snapshot.retainall", "1day").unwrap();
+
+        let repo = Repo::new(repo);
+        for content in ["one", "two"] {
+            std::fs::write(temp_dir.path().join("file"), content).unwrap();
+            repo.snapshot().unwrap();
+        }
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_ref_name = Repo::snapshot_branch(&config, &current_branch, None);
+        let index_path = repo.index_cache_path(&current_branch, &snapshot_ref_name);
+        assert!(index_path.is_file());
+        assert!(index_key_path(&index_path).is_file());
+        // as if its snapshot ref had been deleted
+        let stale_path = repo.index_cache_path("other", "refs/heads/snapshot/other");
+        std::fs::copy(&index_path, &stale_path).unwrap();
+
+        let later = SystemTime::now() + Duration::from_secs(14 * 24 * 60 * 60);
+        assert_eq!(1, repo.prune_at(later, false).unwrap());
+        assert!(!index_path.exists());
+        assert!(!index_key_path(&index_path).exists());
+        assert!(!stale_path.exists());
+
+        std::fs::write(temp_dir.path().join("file"), "three").unwrap();
+        repo.snapshot().unwrap();
+        assert!(index_path.is_file());
+    }
+
+    #[test]
+    fn prune_no_policy() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+
+        let later = SystemTime::now() + Duration::from_secs(14 * 24 * 60 * 60);
+        assert_eq!(0, repo.prune_at(later, false).unwrap());
+        assert_eq!(2, repo.snapshots().unwrap().count());
+    }
+
+    #[test]
+    fn snapshot_reset_on_commit_drop() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_str("snapshot.resetoncommit", "drop").unwrap();
+        std::fs::write(temp_dir.path().join("file"), "one").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        commit_all(repo.git_repo());
+        std::fs::write(temp_dir.path().join("file"), "two").unwrap();
+        repo.snapshot().unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        let head = repo.git_repo().head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(1, repo.snapshots().unwrap().count());
+        assert_eq!(
+            head.id(),
+            repo.snapshot_base(&snapshot).unwrap().unwrap().id()
+        );
+    }
+
+    #[test]
+    fn snapshot_reset_on_commit_archive() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_str("snapshot.resetoncommit", "archive").unwrap();
+
+        let repo = Repo::new(repo);
+        for content in ["one", "two"] {
+            std::fs::write(temp_dir.path().join("file"), content).unwrap();
+            repo.snapshot().unwrap();
+        }
+        let old_tip = snapshot_commit(&repo);
+        commit_all(repo.git_repo());
+        std::fs::write(temp_dir.path().join("file"), "three").unwrap();
+        repo.snapshot().unwrap();
+
+        let snapshots: Vec<Commit> = repo.snapshots().unwrap().map(Result::unwrap).collect();
+        assert_eq!(2, snapshots.len());
+        let archive = &snapshots[1];
+        assert_eq!(old_tip.tree_id(), archive.tree_id());
+        assert_eq!(Some("Archive of 2 snapshots"), archive.summary());
+    }
+
+    #[test]
+    fn snapshot_reset_on_commit_off() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        std::fs::write(temp_dir.path().join("file"), "one").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        commit_all(repo.git_repo());
+        std::fs::write(temp_dir.path().join("file"), "two").unwrap();
+        repo.snapshot().unwrap();
+
+        assert_eq!(2, repo.snapshots().unwrap().count());
+    }
+
+    #[test]
+    fn snapshot_include_exclude() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join(".gitignore"), ".env.local\nbuild/\n").unwrap();
+        std::fs::write(path.join(".env.local"), "SECRET=1").unwrap();
+        std::fs::create_dir(path.join("build")).unwrap();
+        std::fs::write(path.join("build/output"), "output").unwrap();
+        std::fs::write(path.join("dump.bin"), "dump").unwrap();
+        std::fs::write(path.join("file"), "file").unwrap();
+
+        config.set_str("snapshot.include", ".env.local").unwrap();
+        config.set_str("snapshot.exclude", "*.bin").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        assert!(tree.get_name(".env.local").is_some());
+        assert!(tree.get_name("file").is_some());
+        assert!(tree.get_name("build").is_none());
+        assert!(tree.get_name("dump.bin").is_none());
+
+        // Files snapshotted before are dropped once excluded
+        config.set_str("snapshot.exclude", "file").unwrap();
+        repo.snapshot().unwrap();
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        assert!(tree.get_name("file").is_none());
+        assert!(tree.get_name("dump.bin").is_some());
+
+        // and once ignored by a .gitignore in any directory
+        std::fs::create_dir(path.join("dir")).unwrap();
+        std::fs::write(path.join("dir/.gitignore"), "").unwrap();
+        std::fs::write(path.join("dir/notes"), "notes").unwrap();
+        repo.snapshot().unwrap();
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        assert!(tree.get_path(Path::new("dir/notes")).is_ok());
+        std::fs::write(path.join("dir/.gitignore"), "notes\n").unwrap();
+        repo.snapshot().unwrap();
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        assert!(tree.get_path(Path::new("dir/notes")).is_err());
+    }
+
+    #[test]
+    fn snapshot_global_ignore() {
+        let temp_dir = tempdir().unwrap();
+        let xdg_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join("global-ignored"), "ignored").unwrap();
+        std::fs::create_dir(xdg_dir.path().join("git")).unwrap();
+        std::fs::write(xdg_dir.path().join("git/ignore"), "").unwrap();
+        std::env::set_var("XDG_CONFIG_HOME", xdg_dir.path());
+        // libgit2 reads XDG_CONFIG_HOME once per process
+        unsafe {
+            git2::opts::set_search_path(git2::ConfigLevel::XDG, xdg_dir.path().join("git"))
+                .unwrap();
+        }
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        assert!(tree.get_name("global-ignored").is_some());
+
+        // Files snapshotted before are dropped once ignored by the global ignore file
+        std::fs::write(xdg_dir.path().join("git/ignore"), "global-ignored\n").unwrap();
+        std::fs::write(path.join("file"), "file").unwrap();
+        repo.snapshot().unwrap();
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        assert!(tree.get_name("global-ignored").is_none());
+    }
+
+    #[test]
+    fn snapshot_max_file_size() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join("small"), "small").unwrap();
+        std::fs::write(path.join("large"), vec![0; 2048]).unwrap();
+        config.set_str("snapshot.maxfilesize", "1k").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        let tree = snapshot.tree().unwrap();
+        assert!(tree.get_name("small").is_some());
+        assert!(tree.get_name("large").is_none());
+        assert_eq!(
+            vec![(PathBuf::from("large"), 2048)],
+            repo.snapshot_skipped(&snapshot)
+        );
+
+        // A snapshotted file that grew too large is dropped too
+        std::fs::write(path.join("small"), vec![0; 4096]).unwrap();
+        repo.snapshot().unwrap();
+        let snapshot = snapshot_commit(&repo);
+        assert!(snapshot.tree().unwrap().get_name("small").is_none());
+        assert_eq!(2, repo.snapshot_skipped(&snapshot).len());
+    }
+
+    #[test]
+    fn snapshot_max_file_size_symlink() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join("large"), vec![0; 2048]).unwrap();
+        std::os::unix::fs::symlink("large", path.join("link")).unwrap();
+        config.set_str("snapshot.maxfilesize", "1k").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        // The link is measured by itself, not by the file it points to
+        let snapshot = snapshot_commit(&repo);
+        assert!(snapshot.tree().unwrap().get_name("link").is_some());
+        assert_eq!(
+            vec![(PathBuf::from("large"), 2048)],
+            repo.snapshot_skipped(&snapshot)
+        );
+    }
+
+    #[test]
+    fn restore_max_file_size() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join("file"), "one").unwrap();
+        config.set_str("snapshot.maxfilesize", "1k").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let first = snapshot_commit(&repo);
+
+        std::fs::write(path.join("file"), "two").unwrap();
+        std::fs::write(path.join("large"), vec![0; 2048]).unwrap();
+
+        let changed = repo.restore(&first, false).unwrap();
+        assert_eq!(vec![PathBuf::from("file")], changed);
+        assert_eq!("one", std::fs::read_to_string(path.join("file")).unwrap());
+
+        // The skipped file isn't deleted
+        assert_eq!(2048, std::fs::metadata(path.join("large")).unwrap().len());
+    }
+
+    fn test_repo_with_submodule(path: &Path, origin_path: &Path) -> (Repository, Repository) {
+        let (origin, _) = test_repo(origin_path);
+        std::fs::write(origin_path.join("file"), "one").unwrap();
+        commit_all(&origin);
+
+        let (repo, _) = test_repo(path);
+        let url = format!("file://{}", origin_path.to_str().unwrap());
+        let mut submodule = repo.submodule(&url, Path::new("sub"), true).unwrap();
+        submodule.clone(None).unwrap();
+        submodule.add_finalize().unwrap();
+        let submodule_repo = submodule.open().unwrap();
+        drop(submodule);
+        let mut config = submodule_repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@test.test").unwrap();
+
+        // Commit the index with the submodule added by add_finalize
+        let tree = repo.index().unwrap().write_tree().unwrap();
+        let tree = repo.find_tree(tree).unwrap();
+        let signature = Signature::now("test", "test").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "", &tree, &[])
+            .unwrap();
+        drop(tree);
+
+        (repo, submodule_repo)
+    }
+
+    #[test]
+    fn snapshot_submodule() {
+        let temp_dir = tempdir().unwrap();
+        let origin_dir = tempdir().unwrap();
+        let (repo, submodule_repo) = test_repo_with_submodule(temp_dir.path(), origin_dir.path());
+        let submodule_path = temp_dir.path().join("sub");
+
+        let repo = Repo::new(repo);
+        std::fs::write(submodule_path.join("file"), "two").unwrap();
+        repo.snapshot().unwrap();
+
+        // The submodule is snapshotted for the parent's branch
+        let current_branch = repo.current_branch().unwrap();
+        let config = repo.git_repo().config().unwrap();
+        let submodule_snapshot = submodule_repo
+            .find_reference(&Repo::snapshot_branch(&config, &current_branch, None))
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+
+        let entry = snapshot_commit(&repo)
+            .tree()
+            .unwrap()
+            .get_path(Path::new("sub"))
+            .unwrap();
+        assert_eq!(Some(ObjectType::Commit), entry.kind());
+        assert_eq!(submodule_snapshot.id(), entry.id());
+
+        // Restoring the parent snapshot restores the submodule
+        let snapshot = snapshot_commit(&repo);
+        std::fs::write(submodule_path.join("file"), "three").unwrap();
+        let changed = repo.restore(&snapshot, false).unwrap();
+        assert_eq!(
+            "two",
+            std::fs::read_to_string(submodule_path.join("file")).unwrap()
+        );
+        assert!(changed.contains(&PathBuf::from("sub/file")));
+    }
+
+    #[test]
+    fn snapshot_clean_submodule() {
+        let temp_dir = tempdir().unwrap();
+        let origin_dir = tempdir().unwrap();
+        let (repo, submodule_repo) = test_repo_with_submodule(temp_dir.path(), origin_dir.path());
+
+        let repo = Repo::new(repo);
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+
+        let head = submodule_repo.head().unwrap().target().unwrap();
+        let entry = snapshot_commit(&repo)
+            .tree()
+            .unwrap()
+            .get_path(Path::new("sub"))
+            .unwrap();
+        assert_eq!(head, entry.id());
+
+        // Restoring leaves a submodule recorded at the same commit alone
+        let submodule_path = temp_dir.path().join("sub");
+        std::fs::create_dir_all(submodule_repo.path().join("info")).unwrap();
+        std::fs::write(submodule_repo.path().join("info/exclude"), "untracked\n").unwrap();
+        std::fs::write(submodule_path.join("untracked"), "untracked").unwrap();
+        let snapshot = snapshot_commit(&repo);
+        std::fs::write(temp_dir.path().join("file"), "changed").unwrap();
+        let changed = repo.restore(&snapshot, false).unwrap();
+        assert_eq!(vec![PathBuf::from("file")], changed);
+        assert!(submodule_path.join("untracked").exists());
+        // without a safety snapshot taken in it
+        let config = repo.git_repo().config().unwrap();
+        let snapshot_ref_name =
+            Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), None);
+        assert!(submodule_repo.find_reference(&snapshot_ref_name).is_err());
+    }
+
+    #[test]
+    fn snapshot_lfs() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(
+            path.join(".gitattributes"),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        std::fs::write(path.join("data.bin"), "binary content").unwrap();
+        std::fs::write(path.join("file"), "text").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let snapshot = snapshot_commit(&repo);
+
+        // The snapshot tree holds a pointer to the content in the local LFS store
+        let tree = snapshot.tree().unwrap();
+        let blob = repo
+            .git_repo()
+            .find_blob(tree.get_name("data.bin").unwrap().id())
+            .unwrap();
+        let pointer = LfsPointer::parse(blob.content()).unwrap();
+        assert_eq!(14, pointer.size);
+        let store = LfsStore::new(repo.git_repo().path());
+        assert_eq!(
+            "binary content",
+            std::fs::read_to_string(store.object_path(&pointer.oid)).unwrap()
+        );
+        let blob = repo
+            .git_repo()
+            .find_blob(tree.get_name("file").unwrap().id())
+            .unwrap();
+        assert_eq!(b"text", blob.content());
+
+        // Restoring smudges the pointer back to the content
+        std::fs::write(path.join("data.bin"), "changed").unwrap();
+        repo.restore(&snapshot, false).unwrap();
+        assert_eq!(
+            "binary content",
+            std::fs::read_to_string(path.join("data.bin")).unwrap()
+        );
+    }
+
+    #[test]
+    fn snapshot_lfs_executable() {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        let path = temp_dir.path();
+        std::fs::write(path.join(".gitattributes"), "*.sh filter=lfs -text\n").unwrap();
+        std::fs::write(path.join("run.sh"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(path.join("run.sh"), Permissions::from_mode(0o755)).unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let snapshot = snapshot_commit(&repo);
+        let entry = snapshot
+            .tree()
+            .unwrap()
+            .get_name("run.sh")
+            .unwrap()
+            .to_owned();
+        assert_eq!(0o100755, entry.filemode());
+
+        // Restored as executable with its content
+        std::fs::write(path.join("run.sh"), "changed").unwrap();
+        std::fs::set_permissions(path.join("run.sh"), Permissions::from_mode(0o644)).unwrap();
+        repo.restore(&snapshot, false).unwrap();
+        let metadata = std::fs::metadata(path.join("run.sh")).unwrap();
+        assert_ne!(0, metadata.permissions().mode() & 0o111);
+        assert_eq!(
+            "#!/bin/sh\n",
+            std::fs::read_to_string(path.join("run.sh")).unwrap()
+        );
+    }
+
+    #[test]
+    fn snapshot_lfs_index_cache() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        config.set_bool("core.trustctime", false).unwrap();
+        let path = temp_dir.path();
+        std::fs::write(
+            path.join(".gitattributes"),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        let mtime = SystemTime::now() - Duration::from_secs(3600);
+        let write_file = |content: &str| {
+            let file_path = path.join("data.bin");
+            std::fs::write(&file_path, content).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&file_path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        write_file("one");
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let pointer = |repo: &Repo| {
+            let tree = snapshot_commit(repo).tree().unwrap();
+            let entry = tree.get_name("data.bin").map(|entry| entry.id());
+            entry.map(|id| {
+                let blob = repo.git_repo().find_blob(id).unwrap();
+                LfsPointer::parse(blob.content()).unwrap()
+            })
+        };
+        let first = pointer(&repo).unwrap();
+
+        // An unchanged LFS file isn't read again, so content with the same stat data is missed
+        write_file("two");
+        std::fs::write(path.join("file"), "file").unwrap();
+        repo.snapshot().unwrap();
+        assert_eq!(Some(first.clone()), pointer(&repo));
+
+        std::fs::write(path.join("data.bin"), "three").unwrap();
+        repo.snapshot().unwrap();
+        let store = LfsStore::new(repo.git_repo().path());
+        let third = pointer(&repo).unwrap();
+        assert_ne!(first, third);
+        assert_eq!(
+            "three",
+            std::fs::read_to_string(store.object_path(&third.oid)).unwrap()
+        );
+
+        std::fs::remove_file(path.join("data.bin")).unwrap();
+        repo.snapshot().unwrap();
+        assert_eq!(None, pointer(&repo));
+    }
+
+    #[test]
+    fn test_snapshot_empty_branch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        assert!(check_snapshot_exists(&repo))
+    }
+
+    #[test]
+    fn snapshot_no_changes() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, config) = test_repo_with_files(temp_dir.path());
+
+        commit_all(&repo);
 
-    use super::*;
+        NamedTempFile::new_in(temp_dir.path())
+            .unwrap()
+            .keep()
+            .unwrap();
 
-    use crate::util::tests::*;
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
 
-    const TEST_REMOTE_NAME: &'static str = "test";
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, None);
+        let snapshot_ref = repo
+            .git_repo
+            .resolve_reference_from_short_name(&snapshot_branch)
+            .unwrap();
 
-    fn test_repo_with_files(path: &Path) -> (Repository, Config) {
-        let (repo, config) = test_repo(path);
-        NamedTempFile::new_in(path).unwrap().keep().unwrap();
-        (repo, config)
+        let first_commit = snapshot_ref.peel_to_commit().unwrap();
+
+        repo.snapshot().unwrap();
+
+        let second_commit = snapshot_ref.peel_to_commit().unwrap();
+
+        assert_eq!(first_commit.id(), second_commit.id());
     }
 
-    fn test_repo_with_remote(path: &Path, remote_path: &Path) -> (Repository, Repository, Config) {
-        let (repo, config) = test_repo_with_files(path);
-        let remote_repo = Repository::init_bare(remote_path).unwrap();
-        repo.remote(
-            TEST_REMOTE_NAME,
-            &format!("file://{}", remote_repo.path().to_str().unwrap()),
-        )
-        .unwrap();
-        repo.config()
-            .unwrap()
-            .set_bool(&format!("remote.{}.snapshotenabled", "test"), true)
+    #[test]
+    fn snapshot_index_cache() {
+        const FILES: usize = 2000;
+
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        let (repo, mut config) = test_repo(path);
+        // Rewriting a file always moves its ctime, leave size and mtime to tell it changed
+        config.set_bool("core.trustctime", false).unwrap();
+
+        let mtime = SystemTime::now() - Duration::from_secs(3600);
+        let write_file = |name: &str, content: &str| {
+            let file_path = path.join(name);
+            std::fs::write(&file_path, content).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&file_path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        for i in 0..FILES {
+            write_file(&i.to_string(), &format!("{:05}", i));
+        }
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        // Same size and mtime for every file: if any were read, their new content would show up
+        for i in 0..FILES {
+            write_file(&i.to_string(), "xxxxx");
+        }
+        std::fs::remove_file(path.join("0")).unwrap();
+        std::fs::write(path.join("1"), "changed").unwrap();
+        std::fs::write(path.join("new"), "new").unwrap();
+
+        let outcome = repo.snapshot().unwrap();
+
+        assert_eq!(
+            (1, 1, 1),
+            (outcome.added, outcome.modified, outcome.deleted)
+        );
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        let content = |name: &str| {
+            let blob = repo
+                .git_repo()
+                .find_blob(tree.get_name(name).unwrap().id())
+                .unwrap();
+            String::from_utf8(blob.content().to_vec()).unwrap()
+        };
+        assert_eq!("changed", content("1"));
+        assert_eq!("00002", content("2"));
+        assert_eq!(
+            format!("{:05}", FILES - 1),
+            content(&(FILES - 1).to_string())
+        );
+
+        // Without the index every file is hashed again
+        std::fs::remove_dir_all(repo.git_repo().path().join(INDEX_CACHE_DIR)).unwrap();
+        std::fs::write(path.join("new"), "newer").unwrap();
+        repo.snapshot().unwrap();
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        let blob = repo
+            .git_repo()
+            .find_blob(tree.get_name("2").unwrap().id())
             .unwrap();
-        (repo, remote_repo, config)
+        assert_eq!(b"xxxxx", blob.content());
     }
 
-    fn commit_all(repo: &Repository) {
-        let mut index = Index::new().unwrap();
-        repo.set_index(&mut index).unwrap();
-        index
-            .add_all(&["*"], IndexAddOption::DEFAULT, None)
-            .unwrap();
-        let tree = index.write_tree().unwrap();
-        let tree = repo.find_tree(tree).unwrap();
+    #[test]
+    fn snapshot_index_cache_seeded() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        let (repo, mut config) = test_repo(path);
+        config.set_bool("core.trustctime", false).unwrap();
 
-        let signature = Signature::now("test", "test").unwrap();
+        let mtime = SystemTime::now() - Duration::from_secs(3600);
+        let write_file = |content: &str| {
+            std::fs::write(path.join("file"), content).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(path.join("file"))
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        write_file("one");
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        index.write().unwrap();
 
-        repo.commit(Some("HEAD"), &signature, &signature, "", &tree, &[])
+        // Same size and mtime as staged: the new private index takes the staged blob as is
+        write_file("two");
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let tree = snapshot_commit(&repo).tree().unwrap();
+        let blob = repo
+            .git_repo()
+            .find_blob(tree.get_name("file").unwrap().id())
             .unwrap();
+        assert_eq!(b"one", blob.content());
     }
 
-    pub fn check_snapshot_exists(repo: &Repo) -> bool {
-        let config = repo.git_repo.config().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &repo.current_branch().unwrap());
-        repo.git_repo
-            .resolve_reference_from_short_name(&snapshot_branch)
-            .is_ok()
+    #[test]
+    fn snapshot_branch_config_disabled() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, config) = test_repo_with_files(temp_dir.path());
+
+        let repo = Repo::new(repo);
+
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, None);
+        repo.git_repo()
+            .config()
+            .unwrap()
+            .set_bool(&format!("branch.{}.snapshotenabled", current_branch), false)
+            .unwrap();
+
+        repo.snapshot().unwrap();
+
+        let ref_result = repo
+            .git_repo
+            .resolve_reference_from_short_name(&snapshot_branch);
+
+        assert_eq!(ErrorCode::NotFound, ref_result.err().unwrap().code());
     }
 
     #[test]
-    fn snapshot() {
+    fn snapshot_branch_config_snapshotbranch() {
         let temp_dir = tempdir().unwrap();
         let (repo, _config) = test_repo_with_files(temp_dir.path());
 
-        commit_all(&repo);
+        let repo = Repo::new(repo);
 
-        NamedTempFile::new_in(temp_dir.path())
+        let current_branch = repo.current_branch().unwrap();
+        let snapshot_branch = "snapshottest";
+
+        repo.git_repo()
+            .config()
             .unwrap()
-            .keep()
+            .set_str(
+                &format!("branch.{}.snapshotbranch", current_branch),
+                snapshot_branch,
+            )
             .unwrap();
 
-        let repo = Repo::new(repo);
         repo.snapshot().unwrap();
+
+        assert!(check_snapshot_exists(&repo));
     }
 
     #[test]
-    fn test_snapshot_empty_branch() {
+    fn snapshot_snapshot_config_snapshotbranch() {
         let temp_dir = tempdir().unwrap();
         let (repo, _config) = test_repo_with_files(temp_dir.path());
 
         let repo = Repo::new(repo);
+
+        let snapshot_branch = "snapshottest";
+
+        repo.git_repo()
+            .config()
+            .unwrap()
+            .set_str("snapshot.snapshotbranch", snapshot_branch)
+            .unwrap();
+
         repo.snapshot().unwrap();
 
-        assert!(check_snapshot_exists(&repo))
+        assert!(check_snapshot_exists(&repo));
     }
 
     #[test]
-    fn snapshot_no_changes() {
+    fn snapshot_snapshot_config_env_expansion() {
         let temp_dir = tempdir().unwrap();
-        let (repo, config) = test_repo_with_files(temp_dir.path());
+        let (repo, _config) = test_repo_with_files(temp_dir.path());
 
-        commit_all(&repo);
+        let repo = Repo::new(repo);
+        std::env::set_var("TEST_NAME", "test");
 
-        NamedTempFile::new_in(temp_dir.path())
+        let snapshot_branch = "snapshottest/${TEST_NAME}";
+
+        repo.git_repo()
+            .config()
             .unwrap()
-            .keep()
+            .set_str("snapshot.snapshotbranch", snapshot_branch)
+            .unwrap();
+
+        repo.snapshot().unwrap();
+
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn snapshot_message_template() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo(temp_dir.path());
+        for name in ["a", "b", "c", "d", "e", "f", "g"] {
+            std::fs::write(temp_dir.path().join(name), name).unwrap();
+        }
+        config
+            .set_str(
+                "snapshot.snapshotmessage",
+                "${TRIGGER} snapshot of ${BRANCH} on ${HOSTNAME} by ${USER} at ${TIMESTAMP}\n\n${FILES_CHANGED} files: ${CHANGED_PATHS}",
+            )
+            .unwrap();
+        config.set_str("snapshot.timestampformat", "%s").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot_with(&SnapshotOptions {
+            trigger: Trigger::Watcher,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        let message = snapshot.message().unwrap();
+        assert!(message.starts_with(&format!(
+            "watcher snapshot of {} on {} by {} at {}\n\n7 files: a, b, c, d, e (+2 more)",
+            repo.current_branch().unwrap(),
+            hostname(),
+            username(),
+            snapshot.time().seconds()
+        )));
+    }
+
+    #[test]
+    fn snapshot_signed() {
+        let temp_dir = tempdir().unwrap();
+        let signer_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        config.set_bool("commit.gpgsign", true).unwrap();
+        config
+            .set_str(
+                "gpg.program",
+                stub_signer(signer_dir.path()).to_str().unwrap(),
+            )
+            .unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        let (signature, content) = repo
+            .git_repo()
+            .extract_signature(&snapshot.id(), None)
+            .unwrap();
+        assert_eq!(STUB_SIGNATURE, signature.as_str().unwrap());
+        assert_eq!(
+            std::fs::read_to_string(signer_dir.path().join("input")).unwrap(),
+            content.as_str().unwrap()
+        );
+
+        let index_commit = repo.snapshot_index(&snapshot).unwrap().unwrap();
+        assert!(repo
+            .git_repo()
+            .extract_signature(&index_commit.id(), None)
+            .is_ok());
+    }
+
+    #[test]
+    fn snapshot_signing_failure() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        config.set_bool("snapshot.gpgsign", true).unwrap();
+        config.set_str("gpg.program", "false").unwrap();
+
+        let repo = Repo::new(repo);
+        assert!(matches!(repo.snapshot(), Err(Error::Sign(_))));
+        assert!(!check_snapshot_exists(&repo));
+    }
+
+    #[test]
+    fn snapshot_signature_config() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        config.set_str("snapshot.user.name", "Snapshot").unwrap();
+        config
+            .set_str("snapshot.user.email", "snapshot@test.test")
             .unwrap();
 
         let repo = Repo::new(repo);
         repo.snapshot().unwrap();
 
+        let snapshot = snapshot_commit(&repo);
+        assert_eq!(Some("Snapshot"), snapshot.author().name());
+        assert_eq!(Some("snapshot@test.test"), snapshot.author().email());
+        assert_eq!(Some("Snapshot"), snapshot.committer().name());
+
         let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch);
-        let snapshot_ref = repo
-            .git_repo
-            .resolve_reference_from_short_name(&snapshot_branch)
+        config
+            .set_str(
+                &format!("branch.{}.snapshotusername", current_branch),
+                "Branch",
+            )
             .unwrap();
+        let signature = Repo::snapshot_signature(&config, &current_branch).unwrap();
+        assert_eq!(Some("Branch"), signature.name());
+        assert_eq!(Some("snapshot@test.test"), signature.email());
+    }
 
-        let first_commit = snapshot_ref.peel_to_commit().unwrap();
+    #[test]
+    fn snapshot_signature_fallback() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        config.remove("user.name").unwrap();
+        config.remove("user.email").unwrap();
 
+        let repo = Repo::new(repo);
         repo.snapshot().unwrap();
 
-        let second_commit = snapshot_ref.peel_to_commit().unwrap();
+        // Only falls back to the OS identity if there is no global git identity either
+        let config = repo.git_repo().config().unwrap();
+        let snapshot = snapshot_commit(&repo);
+        assert_eq!(
+            config
+                .get_string("user.name")
+                .unwrap_or_else(|_| username()),
+            snapshot.author().name().unwrap()
+        );
+        assert_eq!(
+            config.get_string("user.email").unwrap_or_else(|_| format!(
+                "{}@{}",
+                username(),
+                hostname()
+            )),
+            snapshot.author().email().unwrap()
+        );
+    }
 
-        assert_eq!(first_commit.id(), second_commit.id());
+    #[test]
+    fn snapshot_notes() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        assert_eq!(
+            None,
+            repo.snapshot_metadata(&snapshot_commit(&repo)).unwrap()
+        );
+
+        config.set_bool("snapshot.notes", true).unwrap();
+        create_temp_file(temp_dir.path());
+        let options = SnapshotOptions {
+            trigger: Trigger::Watcher,
+            paths: vec![PathBuf::from("file")],
+            ..Default::default()
+        };
+        repo.snapshot_with(&options).unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        let metadata = repo.snapshot_metadata(&snapshot).unwrap().unwrap();
+        assert_eq!(
+            SnapshotMetadata {
+                hostname: hostname(),
+                user: username(),
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                trigger: Trigger::Watcher,
+                paths: vec![PathBuf::from("file")],
+            },
+            metadata
+        );
+        let notes_ref = Repo::notes_ref(&config);
+        assert!(notes_ref.starts_with(NOTES_REF_PREFIX));
+        assert!(repo
+            .git_repo()
+            .find_note(Some(&notes_ref), snapshot.id())
+            .unwrap()
+            .message()
+            .unwrap()
+            .contains("\"trigger\": \"watcher\""));
+
+        // The notes ref is pushed with the snapshot branch
+        assert!(remote_repo
+            .find_note(Some(&notes_ref), snapshot.id())
+            .is_ok());
+    }
+
+    #[test]
+    fn snapshot_notes_two_clones() {
+        let remote_dir = tempdir().unwrap();
+        let clone_dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        let remote_repo = Repository::init_bare(remote_dir.path()).unwrap();
+        let remote_url = format!("file://{}", remote_repo.path().to_str().unwrap());
+
+        let mut snapshots = Vec::new();
+        for (host, dir) in ["laptop", "desktop.local"].iter().zip(&clone_dirs) {
+            let (repo, mut config) = test_repo_with_files(dir.path());
+            repo.remote(TEST_REMOTE_NAME, &remote_url).unwrap();
+            config
+                .set_bool("remote.test.snapshotenabled", true)
+                .unwrap();
+            config
+                .set_str("remote.test.snapshotbranch", &format!("snapshot/{}", host))
+                .unwrap();
+            config.set_bool("snapshot.notes", true).unwrap();
+            config.set_str("snapshot.hostname", host).unwrap();
+
+            let repo = Repo::new(repo);
+            let outcome = repo.snapshot().unwrap();
+            assert!(outcome.pushes.iter().all(|push| push.error.is_none()));
+            snapshots.push(outcome.commit.unwrap());
+        }
+
+        // Each clone pushes its notes to a ref of its own
+        for (notes_ref, snapshot) in ["laptop", "desktop-local"].iter().zip(snapshots) {
+            let note = remote_repo
+                .find_note(
+                    Some(&format!("refs/notes/snapshot/{}", notes_ref)),
+                    snapshot,
+                )
+                .unwrap();
+            assert!(note.message().unwrap().contains("\"hostname\""));
+        }
+    }
+
+    // all files in the object database, to check nothing was written
+    fn object_files(repo: &Repository) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut dirs = vec![repo.path().join("objects")];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn dry_run() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let (repo, _remote_repo, _config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        std::fs::write(temp_dir.path().join("modified"), "a").unwrap();
+        std::fs::write(temp_dir.path().join("deleted"), "a").unwrap();
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+        let snapshot = snapshot_commit(&repo);
+
+        std::fs::write(temp_dir.path().join("modified"), "abc").unwrap();
+        std::fs::write(temp_dir.path().join("added"), "abcde").unwrap();
+        std::fs::remove_file(temp_dir.path().join("deleted")).unwrap();
+
+        let objects = object_files(repo.git_repo());
+        let dry_run = repo.dry_run().unwrap();
+
+        let ref_name = Repo::snapshot_branch(
+            &repo.git_repo().config().unwrap(),
+            &repo.current_branch().unwrap(),
+            None,
+        );
+        assert_eq!(
+            DryRun {
+                enabled: true,
+                ref_name: ref_name.clone(),
+                changes: vec![
+                    (ChangeKind::Added, PathBuf::from("added")),
+                    (ChangeKind::Deleted, PathBuf::from("deleted")),
+                    (ChangeKind::Modified, PathBuf::from("modified")),
+                ],
+                index_changed: false,
+                size: 8,
+                skipped: Vec::new(),
+                pushes: vec![(
+                    TEST_REMOTE_NAME.to_owned(),
+                    vec![format!("{}:{}", ref_name, ref_name)]
+                )],
+            },
+            dry_run
+        );
+
+        // Nothing was written
+        assert_eq!(objects, object_files(repo.git_repo()));
+        assert_eq!(snapshot.id(), snapshot_commit(&repo).id());
+
+        // The dry run option doesn't snapshot either
+        repo.snapshot_with(&SnapshotOptions {
+            dry_run: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(objects, object_files(repo.git_repo()));
+        assert_eq!(snapshot.id(), snapshot_commit(&repo).id());
     }
 
     #[test]
-    fn snapshot_branch_config_disabled() {
+    fn dry_run_reset_on_commit() {
         let temp_dir = tempdir().unwrap();
-        let (repo, config) = test_repo_with_files(temp_dir.path());
+        let remote_dir = tempdir().unwrap();
+        let (repo, _remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config.set_str("snapshot.resetoncommit", "drop").unwrap();
+        std::fs::write(temp_dir.path().join("file"), "one").unwrap();
 
         let repo = Repo::new(repo);
-
-        let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch);
-        repo.git_repo()
-            .config()
-            .unwrap()
-            .set_bool(&format!("branch.{}.snapshotenabled", current_branch), false)
-            .unwrap();
-
         repo.snapshot().unwrap();
+        let ref_name = Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), None);
+        std::fs::write(temp_dir.path().join("file"), "two").unwrap();
+        assert_eq!(
+            vec![format!("{}:{}", ref_name, ref_name)],
+            repo.dry_run().unwrap().pushes[0].1
+        );
 
-        let ref_result = repo
-            .git_repo
-            .resolve_reference_from_short_name(&snapshot_branch);
-
-        assert_eq!(ErrorCode::NotFound, ref_result.err().unwrap().code());
+        // Once the branch moved the snapshot ref is replaced and force pushed
+        commit_all(repo.git_repo());
+        assert_eq!(
+            vec![format!("+{}:{}", ref_name, ref_name)],
+            repo.dry_run().unwrap().pushes[0].1
+        );
     }
 
     #[test]
-    fn snapshot_branch_config_snapshotbranch() {
+    fn snapshot_outcome() {
         let temp_dir = tempdir().unwrap();
-        let (repo, _config) = test_repo_with_files(temp_dir.path());
+        let remote_dir = tempdir().unwrap();
+        let (repo, _remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        repo.remote("missing", "file:///nonexistent/repo.git")
+            .unwrap();
+        config
+            .set_bool("remote.missing.snapshotenabled", true)
+            .unwrap();
 
         let repo = Repo::new(repo);
+        let outcome = repo.snapshot().unwrap();
+        let ref_name = Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), None);
+        assert_eq!(Some(snapshot_commit(&repo).id()), outcome.commit);
+        assert_eq!(ref_name, outcome.ref_name);
+        assert_eq!(
+            (1, 0, 0),
+            (outcome.added, outcome.modified, outcome.deleted)
+        );
+        assert_eq!(None, outcome.skip_reason);
+        assert_eq!(2, outcome.pushes.len());
+        let pushed = outcome.pushes.iter().find(|p| p.remote == TEST_REMOTE_NAME);
+        assert_eq!(None, pushed.unwrap().error);
+        let failed = outcome.pushes.iter().find(|p| p.remote == "missing");
+        assert!(failed.unwrap().error.is_some());
 
-        let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = "snapshottest";
+        let json = serde_json::to_value(&outcome).unwrap();
+        assert_eq!(
+            serde_json::json!(outcome.commit.unwrap().to_string()),
+            json["commit"]
+        );
 
-        repo.git_repo()
-            .config()
-            .unwrap()
-            .set_str(
-                &format!("branch.{}.snapshotbranch", current_branch),
-                snapshot_branch,
+        let outcome = repo.snapshot().unwrap();
+        assert_eq!(
+            SnapshotOutcome::skipped(ref_name.clone(), SkipReason::NoChanges),
+            outcome
+        );
+        assert_eq!(
+            serde_json::json!("no-changes"),
+            serde_json::to_value(&outcome).unwrap()["skip_reason"]
+        );
+
+        config
+            .set_bool(
+                &format!("branch.{}.snapshotenabled", repo.current_branch().unwrap()),
+                false,
             )
             .unwrap();
-
-        repo.snapshot().unwrap();
-
-        assert!(check_snapshot_exists(&repo));
+        assert_eq!(
+            Some(SkipReason::Disabled),
+            repo.snapshot().unwrap().skip_reason
+        );
     }
 
     #[test]
-    fn snapshot_snapshot_config_snapshotbranch() {
+    fn snapshot_remote_push_rejected() {
         let temp_dir = tempdir().unwrap();
-        let (repo, _config) = test_repo_with_files(temp_dir.path());
+        let remote_dir = tempdir().unwrap();
+        let (repo, remote_repo, config) = test_repo_with_remote(temp_dir.path(), remote_dir.path());
 
         let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
 
-        let snapshot_branch = "snapshottest";
-
-        repo.git_repo()
-            .config()
-            .unwrap()
-            .set_str("snapshot.snapshotbranch", snapshot_branch)
+        // Diverge the remote snapshot branch so the next push isn't a fast-forward
+        let ref_name = Repo::snapshot_branch(&config, &repo.current_branch().unwrap(), None);
+        let signature = Signature::now("test", "test").unwrap();
+        let tree = remote_repo
+            .find_tree(remote_repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        remote_repo
+            .commit(None, &signature, &signature, "other", &tree, &[])
+            .and_then(|id| remote_repo.reference(&ref_name, id, true, "diverge"))
             .unwrap();
 
-        repo.snapshot().unwrap();
-
-        assert!(check_snapshot_exists(&repo));
+        create_temp_file(temp_dir.path());
+        let outcome = repo.snapshot().unwrap();
+        assert!(matches!(
+            outcome.pushes[0].error,
+            Some(PushError::Rejected(_))
+        ));
     }
 
     #[test]
-    fn snapshot_snapshot_config_env_expansion() {
+    fn snapshot_push_failure_policy() {
         let temp_dir = tempdir().unwrap();
-        let (repo, _config) = test_repo_with_files(temp_dir.path());
-
+        let remote_dir = tempdir().unwrap();
+        let (repo, _remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        repo.remote("missing", "file:///nonexistent/repo.git")
+            .unwrap();
+        config
+            .set_bool("remote.missing.snapshotenabled", true)
+            .unwrap();
         let repo = Repo::new(repo);
-        std::env::set_var("TEST_NAME", "test");
 
-        let snapshot_branch = "snapshottest/${TEST_NAME}";
+        // One of two remotes failing doesn't fail the snapshot with the all policy
+        config.set_str("snapshot.failonpush", "all").unwrap();
+        let outcome = repo.snapshot().unwrap();
+        assert!(outcome.commit.is_some());
 
-        repo.git_repo()
-            .config()
-            .unwrap()
-            .set_str("snapshot.snapshotbranch", snapshot_branch)
-            .unwrap();
+        config.set_str("snapshot.failonpush", "any").unwrap();
+        create_temp_file(temp_dir.path());
+        let outcome = match repo.snapshot() {
+            Err(Error::Push(outcome)) => outcome,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        let failures = outcome.push_failures();
+        assert_eq!(1, failures.len());
+        assert_eq!("missing", failures[0].0);
 
-        repo.snapshot().unwrap();
+        // The snapshot is saved regardless and the failed push doesn't block a restore
+        assert_eq!(outcome.commit, Some(snapshot_commit(&repo).id()));
+        let snapshot = snapshot_commit(&repo);
+        std::fs::write(temp_dir.path().join("file"), "changed").unwrap();
+        repo.restore(&snapshot, false).unwrap();
+        assert!(!temp_dir.path().join("file").exists());
 
-        assert!(check_snapshot_exists(&repo));
+        // A remote that can't be pushed to for its name counts as failed too
+        config
+            .set_bool("remote.missing.snapshotenabled", false)
+            .unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(temp_dir.path().join(".git/config"))
+            .unwrap();
+        file.write_all(b"[remote \"bad\xff\"]\n").unwrap();
+        file.write_all(b"\turl = file:///nonexistent/repo.git\n\tsnapshotenabled = true\n")
+            .unwrap();
+        create_temp_file(temp_dir.path());
+        let outcome = match repo.snapshot() {
+            Err(Error::Push(outcome)) => outcome,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        let failures = outcome.push_failures();
+        assert_eq!(1, failures.len());
+        assert_eq!("bad\u{fffd}", failures[0].0);
     }
 
     #[test]
@@ -459,7 +4008,7 @@ pub mod tests {
         repo.snapshot().unwrap();
 
         let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch);
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, None);
 
         assert_eq!(
             None,
@@ -488,7 +4037,7 @@ pub mod tests {
         repo.snapshot().unwrap();
 
         let current_branch = repo.current_branch().unwrap();
-        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch);
+        let snapshot_branch = Repo::snapshot_branch(&config, &current_branch, None);
 
         assert_eq!(
             ErrorCode::NotFound,
@@ -529,19 +4078,428 @@ pub mod tests {
     }
 
     #[test]
-    fn snapshot_invalid_head() {
+    fn snapshot_branch_ref_namespace() {
         let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
 
-        let (repo, _config) = test_repo_with_files(temp_dir.path());
+        assert_eq!("refs/heads/", Repo::ref_namespace(&config));
+        assert_eq!(
+            "refs/heads/snapshot/main",
+            Repo::snapshot_branch(&config, "main", None)
+        );
+        assert_eq!(
+            "refs/heads/snapshot/worktrees/linked/main",
+            Repo::snapshot_branch(&config, "main", Some("linked"))
+        );
+
+        config
+            .set_str("snapshot.refnamespace", "refs/snapshots")
+            .unwrap();
+        assert_eq!("refs/snapshots/", Repo::ref_namespace(&config));
+        assert_eq!(
+            "refs/snapshots/main",
+            Repo::snapshot_branch(&config, "main", None)
+        );
+        assert_eq!(
+            "refs/snapshots/detached/abc",
+            Repo::detached_snapshot_branch(&config, "abc", None)
+        );
+
+        config
+            .set_str("snapshot.snapshotbranch", "wip/${BRANCH}")
+            .unwrap();
+        assert_eq!(
+            "refs/snapshots/wip/main",
+            Repo::snapshot_branch(&config, "main", None)
+        );
+    }
+
+    #[test]
+    fn snapshot_ref_namespace() {
+        let temp_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+
+        let (repo, remote_repo, mut config) =
+            test_repo_with_remote(temp_dir.path(), remote_dir.path());
+        config
+            .set_str("snapshot.refnamespace", "refs/snapshots/")
+            .unwrap();
+
+        let repo = Repo::new(repo);
+        let current_branch = repo.current_branch().unwrap();
+        let outcome = repo.snapshot().unwrap();
+
+        let ref_name = format!("refs/snapshots/{}", current_branch);
+        assert_eq!(ref_name, outcome.ref_name);
+        let snapshot = repo.git_repo().find_reference(&ref_name).unwrap();
+        assert_eq!(
+            snapshot.target(),
+            repo.latest_snapshot().unwrap().map(|commit| commit.id())
+        );
+        assert_eq!(snapshot.target(), remote_repo.refname_to_id(&ref_name).ok());
+
+        // snapshots aren't listed as branches, locally or on the remote
+        for git_repo in [repo.git_repo(), &remote_repo] {
+            let branches = git_repo
+                .branches(None)
+                .unwrap()
+                .map(|branch| branch.unwrap().0.name().unwrap().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            assert!(branches.iter().all(|branch| !branch.contains("snapshot")));
+        }
+
+        // a remote snapshot branch is relative to the namespace
+        config
+            .set_str(
+                &format!("remote.{}.snapshotbranch", TEST_REMOTE_NAME),
+                "remote/${BRANCH}",
+            )
+            .unwrap();
+        create_temp_file(temp_dir.path());
+        repo.snapshot().unwrap();
+        assert!(remote_repo
+            .find_reference(&format!("refs/snapshots/remote/{}", current_branch))
+            .is_ok());
+    }
+
+    #[test]
+    fn snapshot_shadow() {
+        let temp_dir = tempdir().unwrap();
+        let shadow_dir = tempdir().unwrap();
+        let remote_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        let (repo, remote_repo, mut config) = test_repo_with_remote(path, remote_dir.path());
+        std::fs::write(path.join("a"), "one").unwrap();
+        std::fs::create_dir(path.join("dir")).unwrap();
+        std::fs::write(path.join("dir/b"), "unchanged").unwrap();
+        commit_all(&repo);
+        let head = repo.head().unwrap().target().unwrap();
+        std::fs::write(path.join("a"), "two").unwrap();
+
+        config.set_bool("snapshot.shadow", true).unwrap();
+        config
+            .set_str("snapshot.shadowdir", shadow_dir.path().to_str().unwrap())
+            .unwrap();
+        let objects = object_files(&repo);
+        let refs = repo.references().unwrap().count();
+
+        // commit_all leaves an in-memory index on the handle
+        let repo = Repo::open(Repository::open(path).unwrap()).unwrap();
+        assert!(repo.is_shadow());
+        assert!(repo.git_repo().path().starts_with(shadow_dir.path()));
+        let first = repo.snapshot().unwrap().commit.unwrap();
+        std::fs::write(path.join("a"), "three").unwrap();
+        repo.snapshot().unwrap();
+
+        // The project's git dir is left untouched
+        let project = repo.project_repo();
+        assert_eq!(objects, object_files(project));
+        assert_eq!(refs, project.references().unwrap().count());
+
+        // Snapshots are found, listed and restored from the shadow
+        let snapshot = snapshot_commit(&repo);
+        assert_eq!(snapshot.id(), repo.latest_snapshot().unwrap().unwrap().id());
+        assert_eq!(
+            Some(head),
+            repo.snapshot_base(&snapshot).unwrap().map(|c| c.id())
+        );
+        assert_eq!(2, repo.snapshots().unwrap().count());
+        assert_eq!(
+            Some(snapshot.id()),
+            remote_repo
+                .refname_to_id(&format!(
+                    "refs/heads/snapshot/{}",
+                    repo.current_branch().unwrap()
+                ))
+                .ok()
+        );
+
+        let first = repo.find_snapshot(Some(&first.to_string())).unwrap();
+        repo.restore(&first, false).unwrap();
+        assert_eq!("two", std::fs::read_to_string(path.join("a")).unwrap());
+        assert_eq!(objects, object_files(project));
+
+        // The base is only named by its trailer, and the shadow keeps its own copies of the
+        // snapshot objects, so its history survives losing the project's objects to a gc
+        assert!(snapshot.parent_ids().all(|id| id != head));
+        let shadow_path = repo.git_repo().path().to_path_buf();
+        std::fs::remove_file(shadow_path.join("objects/info/alternates")).unwrap();
+        let shadow = Repository::open_bare(&shadow_path).unwrap();
+        for commit in [&snapshot, &first] {
+            let index = repo.snapshot_index(commit).unwrap().unwrap();
+            for id in [commit.id(), index.id()] {
+                let tree = shadow.find_commit(id).unwrap().tree().unwrap();
+                let mut ids = vec![];
+                tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+                    ids.push(entry.id());
+                    TreeWalkResult::Ok
+                })
+                .unwrap();
+                for id in ids {
+                    assert!(shadow.find_object(id, None).is_ok());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_plain_directory() {
+        let temp_dir = tempdir().unwrap();
+        let shadow_dir = tempdir().unwrap();
+        let config_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        std::fs::write(path.join("a"), "one").unwrap();
+        let mut config = Config::open(&config_dir.path().join("config")).unwrap();
+        config
+            .set_str("snapshot.shadowdir", shadow_dir.path().to_str().unwrap())
+            .unwrap();
+
+        assert!(Repo::from_plain_path(path, &config).is_none());
+        let repo = Repo::init_shadow(path, &config).unwrap();
+        assert_eq!(path.file_name().unwrap().to_str().unwrap(), repo.name());
+        let first = repo.snapshot().unwrap().commit.unwrap();
+        assert!(!path.join(".git").exists());
+        // Watched like the worktree of any other repo
+        let worktrees = repo.worktrees().unwrap();
+        assert_eq!(1, worktrees.len());
+        assert_eq!(
+            path.canonicalize().unwrap(),
+            worktrees[0]
+                .git_repo()
+                .workdir()
+                .unwrap()
+                .canonicalize()
+                .unwrap()
+        );
+
+        std::fs::write(path.join("a"), "two").unwrap();
+        let repo = Repo::from_plain_path(path, &config).unwrap();
+        repo.snapshot().unwrap();
+        assert_eq!(2, repo.snapshots().unwrap().count());
+
+        let first = repo.find_snapshot(Some(&first.to_string())).unwrap();
+        repo.restore(&first, false).unwrap();
+        assert_eq!("one", std::fs::read_to_string(path.join("a")).unwrap());
+
+        // Git repositories are shadowed with snapshot.shadow instead
+        let repo_dir = tempdir().unwrap();
+        test_repo(repo_dir.path());
+        assert!(Repo::init_shadow(repo_dir.path(), &config).is_err());
+    }
+
+    pub fn test_repo_with_worktree(path: &Path, worktree_path: &Path) -> (Repository, Repository) {
+        let (repo, _config) = test_repo_with_files(path);
+        commit_all(&repo);
+        let worktree = repo.worktree("linked", worktree_path, None).unwrap();
+        let worktree_repo = Repository::open_from_worktree(&worktree).unwrap();
+        (repo, worktree_repo)
+    }
+
+    #[test]
+    fn snapshot_worktrees() {
+        let temp_dir = tempdir().unwrap();
+        let worktree_dir = tempdir().unwrap();
+        let worktree_path = worktree_dir.path().join("linked");
+        let (repo, worktree_repo) = test_repo_with_worktree(temp_dir.path(), &worktree_path);
+
+        let repo = Repo::new(repo);
+        let worktrees = repo.worktrees().unwrap();
+        assert_eq!(2, worktrees.len());
+        assert_eq!(None, worktrees[0].worktree_name());
+        assert_eq!(Some("linked"), worktrees[1].worktree_name());
+        assert_eq!(2, Repo::new(worktree_repo).worktrees().unwrap().len());
+
+        create_temp_file(temp_dir.path());
+        create_temp_file(&worktree_path);
+        for worktree in &worktrees {
+            worktree.snapshot().unwrap();
+        }
+
+        let main_branch = repo.current_branch().unwrap();
+        let git_repo = repo.git_repo();
+        let main_snapshot = git_repo
+            .resolve_reference_from_short_name(&format!("snapshot/{}", main_branch))
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        let linked_snapshot = git_repo
+            .resolve_reference_from_short_name("snapshot/worktrees/linked/linked")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_ne!(main_snapshot.tree_id(), linked_snapshot.tree_id());
+        assert_eq!(
+            linked_snapshot.id(),
+            worktrees[1].latest_snapshot().unwrap().unwrap().id()
+        );
+    }
+
+    #[test]
+    fn snapshot_worktrees_config_snapshotbranch() {
+        let temp_dir = tempdir().unwrap();
+        let worktree_dir = tempdir().unwrap();
+        let worktree_path = worktree_dir.path().join("linked");
+        let (repo, worktree_repo) = test_repo_with_worktree(temp_dir.path(), &worktree_path);
+        repo.config()
+            .unwrap()
+            .set_str("snapshot.snapshotbranch", "wip/${WORKTREE}")
+            .unwrap();
+
+        create_temp_file(temp_dir.path());
+        create_temp_file(&worktree_path);
+        Repo::new(worktree_repo).snapshot().unwrap();
+        Repo::new(repo).snapshot().unwrap();
+
+        let repo = Repository::open(temp_dir.path()).unwrap();
+        assert!(repo.resolve_reference_from_short_name("wip/main").is_ok());
+        assert!(repo.resolve_reference_from_short_name("wip/linked").is_ok());
+    }
+
+    #[test]
+    fn snapshot_detached_head() {
+        let temp_dir = tempdir().unwrap();
+
+        let (repo, config) = test_repo_with_files(temp_dir.path());
 
         commit_all(&repo);
 
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        repo.set_head_detached(head).unwrap();
+        create_temp_file(temp_dir.path());
+
+        let repo = Repo::new(repo);
+
+        assert!(matches!(
+            repo.current_branch().err().unwrap(),
+            Error::InvalidHead
+        ));
+        repo.snapshot().unwrap();
+
+        let snapshot_branch = Repo::detached_snapshot_branch(&config, &head.to_string(), None);
+        assert_eq!(
+            format!("refs/heads/snapshot/detached/{}", head),
+            snapshot_branch
+        );
+        let snapshot = repo
+            .git_repo()
+            .resolve_reference_from_short_name(&snapshot_branch)
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(head, repo.snapshot_base(&snapshot).unwrap().unwrap().id());
+        assert_eq!(snapshot.id(), repo.latest_snapshot().unwrap().unwrap().id());
+
+        // Snapshots of any detached commit share one private index
+        let index_dir = repo.git_repo().path().join(INDEX_CACHE_DIR);
+        assert!(index_dir.join("HEAD/main").is_file());
+        assert!(!index_dir.join(&snapshot_branch).exists());
+    }
+
+    #[test]
+    fn snapshot_detached_head_config_detachedbranch() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, mut config) = test_repo_with_files(temp_dir.path());
+        commit_all(&repo);
         repo.set_head_detached(repo.head().unwrap().peel_to_commit().unwrap().id())
             .unwrap();
+        config
+            .set_str("snapshot.detachedbranch", "snapshot/detached")
+            .unwrap();
 
         let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        assert!(repo
+            .git_repo()
+            .resolve_reference_from_short_name("snapshot/detached")
+            .is_ok());
+    }
+
+    fn commit_file(repo: &Repository, name: &str, content: &str) -> Oid {
+        std::fs::write(repo.workdir().unwrap().join(name), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let signature = Signature::now("test", "test").unwrap();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            name,
+            &tree,
+            parent.as_ref().as_slice(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn snapshot_merge_conflict() {
+        let temp_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+
+        let base = commit_file(&repo, "file", "base");
+        let main = repo.head().unwrap().name().unwrap().to_owned();
+        repo.branch("other", &repo.find_commit(base).unwrap(), false)
+            .unwrap();
+        commit_file(&repo, "file", "main");
 
-        assert!(matches!(repo.snapshot().err().unwrap(), Error::InvalidHead));
+        let mut checkout = CheckoutBuilder::new();
+        repo.set_head("refs/heads/other").unwrap();
+        repo.checkout_head(Some(checkout.force())).unwrap();
+        let other = commit_file(&repo, "file", "other");
+        repo.set_head(&main).unwrap();
+        repo.checkout_head(Some(checkout.force())).unwrap();
+
+        let other = repo.find_annotated_commit(other).unwrap();
+        repo.merge(&[&other], None, None).unwrap();
+        assert!(repo.index().unwrap().has_conflicts());
+        drop(other);
+
+        let repo = Repo::new(repo);
+        repo.snapshot().unwrap();
+
+        let snapshot = snapshot_commit(&repo);
+        let message = snapshot.message().unwrap();
+        assert_eq!(Some("merge"), trailer(message, STATE_TRAILER));
+
+        // Our side of the conflict is kept in the index tree
+        let index_tree = repo
+            .snapshot_index(&snapshot)
+            .unwrap()
+            .unwrap()
+            .tree()
+            .unwrap();
+        let blob = repo
+            .git_repo()
+            .find_blob(index_tree.get_name("file").unwrap().id())
+            .unwrap();
+        assert_eq!(b"main", blob.content());
+
+        // Restoring brings back all sides of the conflict
+        let conflict_ids = |repo: &Repository| {
+            let index = repo.index().unwrap();
+            let conflict = index.conflicts().unwrap().next().unwrap().unwrap();
+            [conflict.ancestor, conflict.our, conflict.their].map(|entry| entry.unwrap().id)
+        };
+        let conflicted = conflict_ids(repo.git_repo());
+        let mut index = repo.git_repo().index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        index.write().unwrap();
+        assert!(!repo.git_repo().index().unwrap().has_conflicts());
+
+        repo.restore(&snapshot, true).unwrap();
+        assert_eq!(conflicted, conflict_ids(repo.git_repo()));
+        assert_eq!(
+            1,
+            repo.git_repo()
+                .index()
+                .unwrap()
+                .conflicts()
+                .unwrap()
+                .count()
+        );
     }
 
     #[test]
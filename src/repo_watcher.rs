@@ -9,8 +9,8 @@ use std::{
 };
 
 use crate::{
-    watcher::{WatchMode, Watcher},
-    Error, Repo,
+    watcher::{Handler, WatchMode, Watcher},
+    Error, Repo, SnapshotOptions, Trigger,
 };
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -31,6 +31,40 @@ pub struct RepoConfig {
 
 type SyncWatcher = Arc<Mutex<Watcher>>;
 
+// Snapshots the repo at the watched path, passing along the paths that triggered it
+struct SnapshotHandler;
+
+impl Handler for SnapshotHandler {
+    fn handle(&mut self, path: PathBuf) {
+        self.handle_changes(path, Vec::new());
+    }
+
+    fn handle_changes(&mut self, path: PathBuf, changed: Vec<PathBuf>) {
+        let paths: Vec<PathBuf> = changed
+            .iter()
+            .filter_map(|p| p.strip_prefix(&path).ok())
+            .filter(|rel| !rel.starts_with(".git"))
+            .map(Path::to_path_buf)
+            .collect();
+
+        if let Ok(repo) = Repo::from_path(&path) {
+            // Changes only to git internals or ignored paths don't need a snapshot
+            let ignored = |rel: &PathBuf| repo.is_ignored(rel).unwrap_or(false);
+            if !changed.is_empty() && paths.iter().all(ignored) {
+                return;
+            }
+            let options = SnapshotOptions {
+                trigger: Trigger::Watcher,
+                paths,
+                ..Default::default()
+            };
+            if let Err(err) = repo.snapshot_with(&options) {
+                error!(target: repo.name(), "snapshot error: {:?}", err);
+            }
+        }
+    }
+}
+
 pub struct RepoWatcher(SyncWatcher);
 
 impl Default for WatchConfig {
@@ -43,12 +77,6 @@ impl Default for WatchConfig {
     }
 }
 
-impl Default for WatchMode {
-    fn default() -> Self {
-        Self::Event
-    }
-}
-
 impl RepoWatcher {
     pub fn new(config: WatchConfig) -> Result<Self, Error> {
         Ok(Self(Arc::new(Mutex::new(Self::watcher(config)?))))
@@ -58,10 +86,8 @@ impl RepoWatcher {
         let config_path = config_path.as_ref();
         let config = Self::open_config(config_path)?;
 
-        let debounce_period = config.debounce_period.clone();
-
         let watcher = Self::new(config)?;
-        Self::watch_config(watcher.0.clone(), config_path, debounce_period)?;
+        Self::watch_config(watcher.0.clone(), config_path)?;
 
         Ok(watcher)
     }
@@ -72,33 +98,25 @@ impl RepoWatcher {
     }
 
     fn watcher(config: WatchConfig) -> Result<Watcher, Error> {
-        let debounce_period = config.debounce_period.clone();
-        let mut watcher = Watcher::new(&config.mode, debounce_period.clone())?;
+        let debounce_period = config.debounce_period;
+        let mut watcher = Watcher::new(&config.mode, debounce_period)?;
         for RepoConfig { path } in &config.repos {
-            let handler = move |path: PathBuf| {
-                let rel = path.strip_prefix(&path).unwrap();
-                if rel.starts_with(".git") {
-                    return;
-                }
-
-                if let Ok(repo) = Repo::from_path(&path) {
-                    if !repo.is_ignored(rel).unwrap_or(false) {
-                        if let Err(err) = repo.snapshot() {
-                            error!(target: repo.name(), "snapshot error: {:?}", err);
-                        }
-                    }
-                }
+            // Watch every worktree of the repo, each one snapshots into its own branch
+            let worktree_paths = match Repo::from_path(path).and_then(|repo| repo.worktrees()) {
+                Ok(worktrees) => worktrees
+                    .iter()
+                    .filter_map(|worktree| worktree.git_repo().workdir().map(Path::to_path_buf))
+                    .collect(),
+                Err(_) => vec![path.clone()],
             };
-            watcher.watch_path(canonicalize(path)?, Box::new(handler))?;
+            for worktree_path in worktree_paths {
+                watcher.watch_path(canonicalize(worktree_path)?, Box::new(SnapshotHandler))?;
+            }
         }
         Ok(watcher)
     }
 
-    fn watch_config(
-        watcher: SyncWatcher,
-        config_path: &Path,
-        period: Duration,
-    ) -> Result<(), Error> {
+    fn watch_config(watcher: SyncWatcher, config_path: &Path) -> Result<(), Error> {
         watcher.clone().lock().unwrap().watch_path(
             config_path,
             Box::new(move |path: PathBuf| {
@@ -108,7 +126,7 @@ impl RepoWatcher {
                         let mut w_lock = watcher.lock().unwrap();
                         *w_lock = w;
                         drop(w_lock);
-                        if let Err(err) = Self::watch_config(watcher.clone(), &path, period) {
+                        if let Err(err) = Self::watch_config(watcher.clone(), &path) {
                             error!("{:?}", err);
                         }
                     }
@@ -146,7 +164,7 @@ mod tests {
     use tokio::time::sleep;
 
     use crate::{
-        tests::check_snapshot_exists,
+        tests::{check_snapshot_exists, test_repo_with_worktree},
         util::tests::{create_temp_file, test_repo},
         watcher::WatchMode,
         Repo,
@@ -181,6 +199,51 @@ mod tests {
         assert!(check_snapshot_exists(&repo));
     }
 
+    #[test]
+    fn snapshot_handler_ignored_paths() {
+        let repo_path = tempdir().unwrap();
+        let path = repo_path.path().canonicalize().unwrap();
+        let (repo, _) = test_repo(&path);
+        std::fs::write(path.join(".gitignore"), "target/\n").unwrap();
+        std::fs::create_dir(path.join("target")).unwrap();
+        std::fs::write(path.join("target/output"), "output").unwrap();
+        let repo = Repo::new(repo);
+
+        let mut handler = SnapshotHandler;
+        handler.handle_changes(
+            path.clone(),
+            vec![path.join("target/output"), path.join(".git/index")],
+        );
+        assert!(!check_snapshot_exists(&repo));
+
+        handler.handle_changes(path.clone(), vec![path.join(".gitignore")]);
+        assert!(check_snapshot_exists(&repo));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn repo_watcher_worktrees() {
+        let repo_path = tempdir().unwrap();
+        let worktree_dir = tempdir().unwrap();
+        let worktree_path = worktree_dir.path().join("linked");
+        let (_, worktree_repo) = test_repo_with_worktree(repo_path.path(), &worktree_path);
+        let worktree_repo = Repo::new(worktree_repo);
+
+        let repo_watcher = RepoWatcher::new(WatchConfig {
+            repos: vec![RepoConfig {
+                path: repo_path.path().to_owned(),
+            }],
+            mode: WatchMode::Event,
+            debounce_period: Duration::from_millis(50),
+        })
+        .unwrap();
+        create_temp_file(&worktree_path);
+
+        sleep(Duration::from_millis(100)).await;
+        drop(repo_watcher);
+
+        assert!(check_snapshot_exists(&worktree_repo));
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn config_file() {
         let repo_path = tempdir().unwrap();
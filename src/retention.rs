@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use git2::Config;
+
+use crate::util::ConfigValue;
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+
+/// Age windows for thinning snapshot history, snapshots older than every window are pruned.
+/// A zero window is disabled.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Keep every snapshot younger than this
+    pub all: Duration,
+    /// Keep the newest snapshot of each hour younger than this
+    pub hourly: Duration,
+    /// Keep the newest snapshot of each day younger than this
+    pub daily: Duration,
+    /// Keep the newest snapshot of each week younger than this
+    pub weekly: Duration,
+}
+
+impl RetentionPolicy {
+    pub fn from_config(config: &Config, current_branch: &str) -> Self {
+        let window = |name: &str| {
+            Duration::from_config(
+                config,
+                &[
+                    &format!("branch.{}.snapshotretain{}", current_branch, name),
+                    &format!("snapshot.retain{}", name),
+                ],
+                Duration::ZERO,
+            )
+        };
+        Self {
+            all: window("all"),
+            hourly: window("hourly"),
+            daily: window("daily"),
+            weekly: window("weekly"),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Selects the snapshots to keep from their commit times in seconds, ordered newest first.
+    /// The newest snapshot is always kept.
+    pub fn retain(&self, now: i64, times: &[i64]) -> Vec<bool> {
+        let windows = [(self.hourly, HOUR), (self.daily, DAY), (self.weekly, WEEK)];
+        let mut buckets = HashSet::new();
+
+        times
+            .iter()
+            .enumerate()
+            .map(|(i, &time)| {
+                let age = Duration::from_secs((now - time).max(0) as u64);
+                if i == 0 || age <= self.all {
+                    // it's the newest of its hour, day and week, so older ones there aren't kept
+                    for (_, period) in windows {
+                        buckets.insert((period, time.div_euclid(period)));
+                    }
+                    return true;
+                }
+                // Only the smallest window containing the snapshot applies
+                windows
+                    .iter()
+                    .find(|(window, _)| age <= *window)
+                    .is_some_and(|&(_, period)| buckets.insert((period, time.div_euclid(period))))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::test_repo;
+    use tempfile::tempdir;
+
+    #[test]
+    fn from_config() {
+        let temp = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp.path());
+        config.set_str("snapshot.retainall", "1day").unwrap();
+        config.set_str("snapshot.retaindaily", "1month").unwrap();
+        config
+            .set_str("branch.main.snapshotretaindaily", "1week")
+            .unwrap();
+
+        let policy = RetentionPolicy::from_config(&config, "main");
+        assert_eq!(
+            RetentionPolicy {
+                all: Duration::from_secs(DAY as u64),
+                hourly: Duration::ZERO,
+                daily: Duration::from_secs(WEEK as u64),
+                weekly: Duration::ZERO,
+            },
+            policy
+        );
+        assert!(policy.is_enabled());
+        assert!(!RetentionPolicy::default().is_enabled());
+    }
+
+    #[test]
+    fn retain() {
+        let policy = RetentionPolicy {
+            all: Duration::from_secs(HOUR as u64),
+            hourly: Duration::from_secs(DAY as u64),
+            daily: Duration::from_secs(WEEK as u64),
+            weekly: Duration::ZERO,
+        };
+        let now = 100 * WEEK;
+        let times = [
+            now - 60,             // within all
+            now - 120,            // within all
+            now - 3 * HOUR + 120, // newest in its hour
+            now - 3 * HOUR + 60,  // same hour
+            now - 3 * DAY + HOUR, // newest in its day
+            now - 3 * DAY + 60,   // same day
+            now - 2 * WEEK,       // older than every window
+        ];
+        assert_eq!(
+            vec![true, true, true, false, true, false, false],
+            policy.retain(now, &times)
+        );
+    }
+
+    #[test]
+    fn retain_newest() {
+        let policy = RetentionPolicy {
+            all: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(vec![true, false], policy.retain(WEEK, &[0, 0]));
+    }
+
+    #[test]
+    fn retain_newest_fills_buckets() {
+        let policy = RetentionPolicy {
+            hourly: Duration::from_secs(DAY as u64),
+            daily: Duration::from_secs(WEEK as u64),
+            ..Default::default()
+        };
+        let now = 100 * WEEK;
+        let times = [
+            now - 2 * HOUR + 120, // newest
+            now - 2 * HOUR + 60,  // same hour
+            now - 3 * HOUR,       // newest in its hour
+            now - 2 * DAY + 60,   // newest in its day
+        ];
+        assert_eq!(vec![true, false, true, true], policy.retain(now, &times));
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs::{copy, write};
+use std::path::{Path, PathBuf};
+
+use git2::{ErrorCode, Repository};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::util::common_dir;
+
+// repository files the project's ignore rules and attributes are read from, copied so the
+// shadow repository sees the same paths as the project
+const SHARED_INFO_FILES: [&str; 2] = ["info/exclude", "info/attributes"];
+
+/// Path of the shadow repository for a project under `dir`, named after the project and a
+/// hash of its path so every project gets its own
+pub fn shadow_path(dir: &Path, project: &Path) -> PathBuf {
+    let project = project
+        .canonicalize()
+        .unwrap_or_else(|_| project.to_path_buf());
+    let hash = format!("{:x}", Sha256::digest(project.to_string_lossy().as_bytes()));
+    // `<worktree>/.git` is named after the worktree
+    let name = match project.file_name() {
+        Some(name) if name == ".git" => project.parent().and_then(Path::file_name),
+        name => name,
+    };
+    let name = name
+        .map(|name| name.to_string_lossy().trim_end_matches(".git").to_owned())
+        .unwrap_or_default();
+    dir.join(format!("{}-{}.git", name, &hash[..16]))
+}
+
+/// Opens the shadow repository of a project under `dir`, creating it if needed. The shadow is
+/// a bare repository reading the project's objects as an alternate and opened with the
+/// project's worktree, so snapshots are written to it without touching the project's git dir.
+pub fn open_shadow(project: &Repository, dir: &Path) -> Result<Repository, Error> {
+    let workdir = project
+        .workdir()
+        .ok_or_else(|| git2::Error::from_str("a shadow repository needs a worktree"))?;
+    // linked worktrees share the shadow of their main repository
+    let common_dir = common_dir(project.path());
+    let common_dir = common_dir.canonicalize().unwrap_or(common_dir);
+    let path = shadow_path(dir, &common_dir);
+
+    match Repository::open_bare(&path) {
+        Ok(_) => {}
+        Err(err) if err.code() == ErrorCode::NotFound => {
+            let shadow = Repository::init_bare(&path)?;
+            // project settings such as core.* and remotes apply to the shadow too
+            shadow
+                .config()?
+                .set_str("include.path", &common_dir.join("config").to_string_lossy())?;
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    // Refreshed on every open in case the project moved
+    write(
+        path.join("objects/info/alternates"),
+        format!("{}\n", common_dir.join("objects").display()),
+    )?;
+    for file in SHARED_INFO_FILES {
+        let src = common_dir.join(file);
+        if src.is_file() {
+            copy(src, path.join(file))?;
+        }
+    }
+
+    let shadow = Repository::open_bare(&path)?;
+    shadow.set_workdir(workdir, false)?;
+    Ok(shadow)
+}
+
+/// Creates the shadow repository of a plain directory that isn't a git repository. The
+/// directory is its worktree, its snapshots are taken of an unborn branch.
+pub fn init_plain_shadow(dir: &Path, path: &Path) -> Result<Repository, Error> {
+    let workdir = path.canonicalize()?;
+    let path = shadow_path(dir, &workdir);
+    let shadow = Repository::init_bare(&path)?;
+    let mut config = shadow.config()?;
+    config.set_bool("core.bare", false)?;
+    config.set_str("core.worktree", &workdir.to_string_lossy())?;
+    // the shadow is snapshotted itself, not into another shadow
+    config.set_bool("snapshot.shadow", false)?;
+    Ok(Repository::open(&path)?)
+}
+
+/// Finds the shadow repository of a plain directory containing `path`
+pub fn find_plain_shadow(dir: &Path, path: &Path) -> Option<Repository> {
+    let path = path.canonicalize().ok()?;
+    path.ancestors()
+        .map(|ancestor| shadow_path(dir, ancestor))
+        .find(|shadow| shadow.is_dir())
+        .and_then(|shadow| Repository::open(shadow).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::{create_temp_file, test_repo};
+    use std::fs::create_dir;
+    use tempfile::tempdir;
+
+    #[test]
+    fn shadow_path_per_project() {
+        let dir = Path::new("/shadows");
+        let path = shadow_path(dir, Path::new("/projects/app/.git"));
+        assert_eq!(Some(dir), path.parent());
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("app-") && name.ends_with(".git"));
+        assert_eq!(path, shadow_path(dir, Path::new("/projects/app/.git")));
+        assert_ne!(path, shadow_path(dir, Path::new("/other/app/.git")));
+        assert!(shadow_path(dir, Path::new("/projects/lib.git"))
+            .to_string_lossy()
+            .contains("/lib-"));
+    }
+
+    #[test]
+    fn open_shadow_alternate() {
+        let temp_dir = tempdir().unwrap();
+        let shadow_dir = tempdir().unwrap();
+        let (repo, _config) = test_repo(temp_dir.path());
+        create_temp_file(temp_dir.path());
+        let blob = repo.blob(b"project").unwrap();
+
+        let shadow = open_shadow(&repo, shadow_dir.path()).unwrap();
+        assert!(shadow.path().starts_with(shadow_dir.path()));
+        assert_eq!(
+            temp_dir.path().canonicalize().unwrap(),
+            shadow.workdir().unwrap().canonicalize().unwrap()
+        );
+        // project objects are readable, new objects stay in the shadow
+        assert!(shadow.find_blob(blob).is_ok());
+        let shadow_blob = shadow.blob(b"shadow").unwrap();
+        assert!(repo.find_blob(shadow_blob).is_err());
+
+        let reopened = open_shadow(&repo, shadow_dir.path()).unwrap();
+        assert_eq!(shadow.path(), reopened.path());
+        assert!(reopened.find_blob(shadow_blob).is_ok());
+    }
+
+    #[test]
+    fn plain_shadow() {
+        let temp_dir = tempdir().unwrap();
+        let shadow_dir = tempdir().unwrap();
+        let nested = temp_dir.path().join("nested");
+        create_dir(&nested).unwrap();
+        assert!(find_plain_shadow(shadow_dir.path(), temp_dir.path()).is_none());
+
+        let shadow = init_plain_shadow(shadow_dir.path(), temp_dir.path()).unwrap();
+        assert!(!shadow.is_bare());
+        assert!(shadow.path().starts_with(shadow_dir.path()));
+        assert_eq!(
+            temp_dir.path().canonicalize().unwrap(),
+            shadow.workdir().unwrap().canonicalize().unwrap()
+        );
+        assert!(!temp_dir.path().join(".git").exists());
+
+        for path in [temp_dir.path(), &nested] {
+            let found = find_plain_shadow(shadow_dir.path(), path).unwrap();
+            assert_eq!(shadow.path(), found.path());
+        }
+    }
+}
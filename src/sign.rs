@@ -0,0 +1,309 @@
+use std::fs::read_to_string;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+use git2::{Config, Signature};
+use tempfile::NamedTempFile;
+
+use crate::error::Error;
+use crate::util::ConfigValue;
+
+const SSH_KEY_PREFIX: &str = "key::";
+
+/// Signature format, from `gpg.format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignFormat {
+    OpenPgp,
+    X509,
+    Ssh,
+}
+
+/// Signs snapshot commits the way `git commit -S` does, by running the configured signing
+/// program over the commit buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signer {
+    pub format: SignFormat,
+    pub program: String,
+    pub key: Option<String>,
+}
+
+impl Signer {
+    /// Returns the signer for a branch, or `None` if snapshots of it aren't signed
+    pub fn from_config(config: &Config, current_branch: &str) -> Option<Self> {
+        let enabled = bool::from_config(
+            config,
+            &[
+                &format!("branch.{}.snapshotgpgsign", current_branch),
+                "snapshot.gpgsign",
+                "commit.gpgsign",
+            ],
+            false,
+        );
+        if !enabled {
+            return None;
+        }
+
+        let format = match String::from_config(config, &["gpg.format"], String::new()).as_str() {
+            "ssh" => SignFormat::Ssh,
+            "x509" => SignFormat::X509,
+            _ => SignFormat::OpenPgp,
+        };
+        let program = match format {
+            SignFormat::OpenPgp => String::from_config(
+                config,
+                &["gpg.openpgp.program", "gpg.program"],
+                "gpg".into(),
+            ),
+            SignFormat::X509 => String::from_config(config, &["gpg.x509.program"], "gpgsm".into()),
+            SignFormat::Ssh => {
+                String::from_config(config, &["gpg.ssh.program"], "ssh-keygen".into())
+            }
+        };
+        let key = String::from_config(config, &["user.signingkey"], String::new());
+
+        Some(Self {
+            format,
+            program,
+            key: Some(key).filter(|key| !key.is_empty()),
+        })
+    }
+
+    /// Signs a commit buffer, returning the detached signature
+    pub fn sign(&self, buffer: &str, committer: &Signature) -> Result<String, Error> {
+        let output = match self.format {
+            SignFormat::OpenPgp | SignFormat::X509 => self.sign_gpg(buffer, committer)?,
+            SignFormat::Ssh => return self.sign_ssh(buffer),
+        };
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(Error::Sign(format!(
+                "{} failed: {}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        String::from_utf8(output.stdout)
+            .map_err(|_| Error::Sign(format!("{} returned an invalid signature", self.program)))
+    }
+
+    fn sign_gpg(&self, buffer: &str, committer: &Signature) -> Result<Output, Error> {
+        // gpg picks the key matching the committer when no signing key is configured
+        let key = self.key.clone().unwrap_or_else(|| {
+            format!(
+                "{} <{}>",
+                committer.name().unwrap_or_default(),
+                committer.email().unwrap_or_default()
+            )
+        });
+        let mut child = Command::new(&self.program)
+            .args(["--status-fd=2", "-bsau", &key])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let written = child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(buffer.as_bytes());
+        // a program exiting without reading the buffer is reported by its exit status
+        match written {
+            Err(err) if err.kind() != ErrorKind::BrokenPipe => Err(err.into()),
+            _ => Ok(child.wait_with_output()?),
+        }
+    }
+
+    fn sign_ssh(&self, buffer: &str) -> Result<String, Error> {
+        let key = self
+            .key
+            .as_deref()
+            .ok_or_else(|| Error::Sign("user.signingkey is required for ssh signing".into()))?;
+
+        // ssh-keygen signs files, writing the signature next to the signed file. The files are
+        // created in a private temporary directory, which is removed with everything in it.
+        let dir = tempfile::Builder::new().prefix("git-snapshot-").tempdir()?;
+        let mut buffer_file = NamedTempFile::new_in(dir.path())?;
+        buffer_file.write_all(buffer.as_bytes())?;
+        let mut command = Command::new(&self.program);
+        command.args(["-Y", "sign", "-n", "git", "-f"]);
+        let literal_key = key
+            .strip_prefix(SSH_KEY_PREFIX)
+            .or_else(|| Some(key).filter(|key| key.starts_with("ssh-")));
+        // kept until ssh-keygen is done with it
+        let mut key_file = None;
+        match literal_key {
+            Some(literal_key) => {
+                let file = key_file.insert(NamedTempFile::new_in(dir.path())?);
+                file.write_all(literal_key.as_bytes())?;
+                command.arg(file.path()).arg("-U");
+            }
+            None => {
+                command.arg(key);
+            }
+        }
+        let output = command
+            .arg(buffer_file.path())
+            .stdin(Stdio::null())
+            .output()?;
+
+        let signature_path = PathBuf::from(format!("{}.sig", buffer_file.path().display()));
+        match read_to_string(signature_path) {
+            Ok(signature) if output.status.success() => Ok(signature),
+            _ => Err(Error::Sign(format!(
+                "{} failed: {}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::util::tests::test_repo;
+    use std::fs::{set_permissions, write, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    pub const STUB_SIGNATURE: &str =
+        "-----BEGIN STUB SIGNATURE-----\nstub\n-----END STUB SIGNATURE-----\n";
+
+    // signing program that logs its arguments and input next to itself and prints a fixed signature
+    pub fn stub_signer(dir: &Path) -> PathBuf {
+        let path = dir.join("signer");
+        write(
+            &path,
+            format!(
+                r#"#!/bin/sh
+echo "$@" > "{dir}/args"
+for last; do :; done
+if [ "$1" = "-Y" ]; then
+    cp "$last" "{dir}/input"
+    cat > "$last.sig" <<EOF
+{sig}EOF
+else
+    cat > "{dir}/input"
+    cat <<EOF
+{sig}EOF
+fi
+"#,
+                dir = dir.display(),
+                sig = STUB_SIGNATURE
+            ),
+        )
+        .unwrap();
+        set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn from_config() {
+        let temp_dir = tempdir().unwrap();
+        let (_repo, mut config) = test_repo(temp_dir.path());
+
+        assert_eq!(None, Signer::from_config(&config, "main"));
+
+        config.set_bool("commit.gpgsign", true).unwrap();
+        assert_eq!(
+            Some(Signer {
+                format: SignFormat::OpenPgp,
+                program: "gpg".into(),
+                key: None,
+            }),
+            Signer::from_config(&config, "main")
+        );
+
+        config.set_str("gpg.format", "ssh").unwrap();
+        config.set_str("user.signingkey", "~/.ssh/id.pub").unwrap();
+        assert_eq!(
+            Some(Signer {
+                format: SignFormat::Ssh,
+                program: "ssh-keygen".into(),
+                key: Some("~/.ssh/id.pub".into()),
+            }),
+            Signer::from_config(&config, "main")
+        );
+
+        config.set_bool("snapshot.gpgsign", false).unwrap();
+        assert_eq!(None, Signer::from_config(&config, "main"));
+        config
+            .set_bool("branch.main.snapshotgpgsign", true)
+            .unwrap();
+        assert!(Signer::from_config(&config, "main").is_some());
+    }
+
+    #[test]
+    fn sign_gpg() {
+        let temp_dir = tempdir().unwrap();
+        let signer = Signer {
+            format: SignFormat::OpenPgp,
+            program: stub_signer(temp_dir.path()).display().to_string(),
+            key: None,
+        };
+        let committer = Signature::now("test", "test@example.com").unwrap();
+
+        assert_eq!(STUB_SIGNATURE, signer.sign("buffer", &committer).unwrap());
+        assert_eq!(
+            "--status-fd=2 -bsau test <test@example.com>\n",
+            read_to_string(temp_dir.path().join("args")).unwrap()
+        );
+        assert_eq!(
+            "buffer",
+            read_to_string(temp_dir.path().join("input")).unwrap()
+        );
+    }
+
+    #[test]
+    fn sign_ssh() {
+        let temp_dir = tempdir().unwrap();
+        let mut signer = Signer {
+            format: SignFormat::Ssh,
+            program: stub_signer(temp_dir.path()).display().to_string(),
+            key: Some("/keys/id.pub".into()),
+        };
+        let committer = Signature::now("test", "test@example.com").unwrap();
+
+        assert_eq!(STUB_SIGNATURE, signer.sign("buffer", &committer).unwrap());
+        let args = read_to_string(temp_dir.path().join("args")).unwrap();
+        assert!(args.starts_with("-Y sign -n git -f /keys/id.pub "));
+        assert_eq!(
+            "buffer",
+            read_to_string(temp_dir.path().join("input")).unwrap()
+        );
+
+        signer.key = Some("key::ssh-ed25519 AAAA".into());
+        signer.sign("buffer", &committer).unwrap();
+        let args = read_to_string(temp_dir.path().join("args")).unwrap();
+        assert!(args.contains(" -U "));
+
+        // The buffer, key and signature files are removed after signing
+        let temp_files: Vec<&str> = args.split_whitespace().skip(5).collect();
+        assert_eq!(3, temp_files.len());
+        for path in temp_files.into_iter().filter(|path| *path != "-U") {
+            assert!(!Path::new(path).exists());
+            assert!(!Path::new(&format!("{}.sig", path)).exists());
+        }
+
+        signer.key = None;
+        assert!(matches!(
+            signer.sign("buffer", &committer),
+            Err(Error::Sign(_))
+        ));
+    }
+
+    #[test]
+    fn sign_failure() {
+        let signer = Signer {
+            format: SignFormat::OpenPgp,
+            program: "false".into(),
+            key: None,
+        };
+        let committer = Signature::now("test", "test@example.com").unwrap();
+        assert!(matches!(
+            signer.sign("buffer", &committer),
+            Err(Error::Sign(_))
+        ));
+    }
+}
@@ -1,9 +1,16 @@
 use std::env::var;
+use std::fmt::Write;
+use std::fs::{read_to_string, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use git2::Config;
+use chrono::{FixedOffset, TimeZone};
+use gethostname::gethostname;
+use git2::{Config, IndexEntry, IndexTime, Oid};
+use humantime::parse_duration;
 use shellexpand::env_with_context_no_errors;
 
-pub const BRANCH_REF_PREFIX: &'static str = "refs/heads/";
+pub const BRANCH_REF_PREFIX: &str = "refs/heads/";
 
 fn get_value<T>(
     config: &Config,
@@ -16,7 +23,7 @@ fn get_value<T>(
             return value;
         }
     }
-    return default_value;
+    default_value
 }
 
 // trait to easily find the first populated key in git config
@@ -44,6 +51,50 @@ impl ConfigValue for bool {
     }
 }
 
+impl ConfigValue for i64 {
+    fn from_config(config: &Config, keys: &[&str], default_value: Self) -> Self
+    where
+        Self: Sized,
+    {
+        get_value(config, &mut Config::get_i64, keys, default_value)
+    }
+}
+
+// multi-valued keys, the first key with any values wins
+impl ConfigValue for Vec<String> {
+    fn from_config(config: &Config, keys: &[&str], default_value: Self) -> Self
+    where
+        Self: Sized,
+    {
+        let mut getter = |config: &Config, key: &str| {
+            let mut values = Vec::new();
+            for entry in &config.multivar(key, None)? {
+                if let Some(value) = entry?.value() {
+                    values.push(value.to_owned());
+                }
+            }
+            if values.is_empty() {
+                return Err(git2::Error::from_str("no values"));
+            }
+            Ok(values)
+        };
+        get_value(config, &mut getter, keys, default_value)
+    }
+}
+
+impl ConfigValue for Duration {
+    fn from_config(config: &Config, keys: &[&str], default_value: Self) -> Self
+    where
+        Self: Sized,
+    {
+        let mut getter = |config: &Config, key: &str| {
+            let value = config.get_string(key)?;
+            parse_duration(&value).map_err(|err| git2::Error::from_str(&err.to_string()))
+        };
+        get_value(config, &mut getter, keys, default_value)
+    }
+}
+
 pub fn expand(input: &str, context: &[(&str, &str)]) -> String {
     env_with_context_no_errors(input, |name| {
         for &(key, val) in context {
@@ -56,10 +107,125 @@ pub fn expand(input: &str, context: &[(&str, &str)]) -> String {
     .to_string()
 }
 
+// name of this machine
+pub fn hostname() -> String {
+    Some(gethostname().to_string_lossy().trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+// name of the OS user running the process
+pub fn username() -> String {
+    var("USER")
+        .or_else(|_| var("USERNAME"))
+        .ok()
+        .filter(|name| !name.is_empty())
+        .or_else(|| {
+            dirs::home_dir()
+                .and_then(|home| home.file_name().and_then(|n| n.to_str()).map(str::to_owned))
+        })
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+// formats a git time in its own timezone with strftime specifiers, falling back to RFC 3339
+// for an invalid format
+pub fn format_time(time: git2::Time, format: &str) -> String {
+    let time = FixedOffset::east_opt(time.offset_minutes() * 60)
+        .and_then(|offset| offset.timestamp_opt(time.seconds(), 0).single());
+    let time = match time {
+        Some(time) => time,
+        None => return String::new(),
+    };
+    let mut output = String::new();
+    match write!(output, "{}", time.format(format)) {
+        Ok(_) => output,
+        Err(_) => time.to_rfc3339(),
+    }
+}
+
+// git dir shared by all worktrees, linked worktree git dirs point to it in `commondir`
+pub fn common_dir(git_dir: &Path) -> PathBuf {
+    read_to_string(git_dir.join("commondir"))
+        .map(|common| git_dir.join(common.trim()))
+        .unwrap_or_else(|_| git_dir.to_path_buf())
+}
+
+// index entry of a worktree file pointing at the blob `id`, with the file's stat data so
+// updating the index skips the file for as long as it's unchanged
+pub fn stat_index_entry(path: &Path, metadata: &Metadata, id: Oid) -> IndexEntry {
+    let time = |time: std::io::Result<SystemTime>| {
+        let time = time
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        IndexTime::new(time.as_secs() as i32, time.subsec_nanos())
+    };
+    // executable if any execute bit is set, like libgit2 records regular files
+    #[cfg(unix)]
+    let (ctime, dev, ino, mode, uid, gid) = {
+        use std::os::unix::fs::MetadataExt;
+        (
+            IndexTime::new(metadata.ctime() as i32, metadata.ctime_nsec() as u32),
+            metadata.dev() as u32,
+            metadata.ino() as u32,
+            if metadata.mode() & 0o111 != 0 {
+                0o100755
+            } else {
+                0o100644
+            },
+            metadata.uid(),
+            metadata.gid(),
+        )
+    };
+    #[cfg(not(unix))]
+    let (ctime, dev, ino, mode, uid, gid) = (time(metadata.created()), 0, 0, 0o100644, 0, 0);
+    IndexEntry {
+        ctime,
+        mtime: time(metadata.modified()),
+        dev,
+        ino,
+        mode,
+        uid,
+        gid,
+        file_size: metadata.len() as u32,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.to_string_lossy().as_bytes().to_vec(),
+    }
+}
+
 pub fn branch_ref_shorthand(ref_name: &str) -> &str {
     ref_name.trim_start_matches(BRANCH_REF_PREFIX)
 }
 
+// appends `key: value` trailers to a commit message
+pub fn with_trailers(message: &str, trailers: &[(&str, String)]) -> String {
+    let trailers: Vec<String> = trailers
+        .iter()
+        .map(|(key, value)| format!("{}: {}", key, value))
+        .collect();
+    format!("{}\n\n{}", message.trim_end(), trailers.join("\n"))
+}
+
+// finds the value of a `key: value` trailer in a commit message
+pub fn trailer<'a>(message: &'a str, key: &str) -> Option<&'a str> {
+    message.lines().rev().find_map(|line| {
+        line.strip_prefix(key)
+            .and_then(|rest| rest.strip_prefix(": "))
+            .map(str::trim)
+    })
+}
+
+// finds all values of a repeated `key: value` trailer in a commit message
+pub fn trailer_values<'a>(message: &'a str, key: &'a str) -> impl Iterator<Item = &'a str> {
+    message.lines().filter_map(move |line| {
+        line.strip_prefix(key)
+            .and_then(|rest| rest.strip_prefix(": "))
+            .map(str::trim)
+    })
+}
+
 #[cfg(test)]
 pub mod tests {
     use std::path::Path;
@@ -107,6 +273,18 @@ pub mod tests {
         assert_eq!(value, result);
     }
 
+    #[test]
+    fn duration_from_config() {
+        let temp = tempdir().unwrap();
+
+        let (_repo, mut config) = test_repo(temp.path());
+        let key = "test.key";
+        config.set_str(key, "1h 30m").unwrap();
+
+        let result = Duration::from_config(&config, &[key], Duration::ZERO);
+        assert_eq!(Duration::from_secs(90 * 60), result);
+    }
+
     #[test]
     fn default_value() {
         let temp = tempdir().unwrap();
@@ -133,4 +311,46 @@ pub mod tests {
         let result = String::from_config(&config, &[key1, key2], String::new());
         assert_eq!(value, result);
     }
+
+    #[test]
+    fn trailers() {
+        let message = with_trailers(
+            "Snapshot\n",
+            &[("Key-One", "one".to_owned()), ("Key-Two", "two".to_owned())],
+        );
+        assert_eq!("Snapshot\n\nKey-One: one\nKey-Two: two", message);
+        assert_eq!(Some("one"), trailer(&message, "Key-One"));
+        assert_eq!(Some("two"), trailer(&message, "Key-Two"));
+        assert_eq!(None, trailer(&message, "Key"));
+
+        let message = with_trailers(
+            "Snapshot",
+            &[("Key", "one".to_owned()), ("Key", "two".to_owned())],
+        );
+        assert_eq!(
+            vec!["one", "two"],
+            trailer_values(&message, "Key").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn format_time_specifiers() {
+        // 2022-06-05 14:03:09 UTC
+        let time = git2::Time::new(1654437789, 0);
+        assert_eq!(
+            "2022-06-05 14:03:09 +0000 100%",
+            format_time(time, "%Y-%m-%d %H:%M:%S %z 100%%")
+        );
+        assert_eq!("1654437789 Sunday", format_time(time, "%s %A"));
+        assert_eq!("2022-06-05T14:03:09+00:00", format_time(time, "%s %Q"));
+
+        let time = git2::Time::new(1654437789, -90);
+        assert_eq!(
+            "2022-06-05T12:33:09-0130",
+            format_time(time, "%Y-%m-%dT%H:%M:%S%z")
+        );
+
+        let time = git2::Time::new(951782400, 0);
+        assert_eq!("2000-02-29", format_time(time, "%Y-%m-%d"));
+    }
 }
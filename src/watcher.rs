@@ -11,12 +11,13 @@ use std::{
     sync::{Arc, Mutex},
     time::Duration,
 };
-use tokio::{sync::mpsc::unbounded_channel, time::sleep};
+use tokio::{sync::mpsc::unbounded_channel, task::JoinHandle, time::sleep};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "mode", content = "mode_config")]
 pub enum WatchMode {
+    #[default]
     Event,
     Poll {
         #[serde(with = "humantime_serde")]
@@ -26,9 +27,14 @@ pub enum WatchMode {
 
 pub trait Handler {
     fn handle(&mut self, path: PathBuf);
+
+    /// Called with the watched path and the paths that changed under it since the last call
+    fn handle_changes(&mut self, path: PathBuf, _changed: Vec<PathBuf>) {
+        self.handle(path);
+    }
 }
 
-impl<F: FnMut(PathBuf) -> ()> Handler for F {
+impl<F: FnMut(PathBuf)> Handler for F {
     fn handle(&mut self, path: PathBuf) {
         (self)(path);
     }
@@ -51,10 +57,8 @@ impl Watcher {
                 Box::new(watcher)
             }
             WatchMode::Poll { interval } => {
-                let watcher = PollWatcher::new(
-                    handler,
-                    Config::default().with_poll_interval(interval.clone()),
-                )?;
+                let watcher =
+                    PollWatcher::new(handler, Config::default().with_poll_interval(*interval))?;
                 Box::new(watcher)
             }
         };
@@ -80,34 +84,52 @@ impl Watcher {
         let handlers_clone = handlers.clone();
 
         tokio::spawn(async move {
+            let mut debouncers: HashMap<PathBuf, JoinHandle<()>> = HashMap::new();
+            // changed paths collected for each watched path until its handler runs
+            let changes: Arc<Mutex<HashMap<PathBuf, Vec<PathBuf>>>> =
+                Arc::new(Mutex::new(HashMap::new()));
+
             while let Some(event_path) = rx.recv().await {
-                let handlers = handlers_clone.lock().unwrap();
-                let mut debouncers = HashMap::new();
-
-                for p in handlers.keys() {
-                    if event_path.starts_with(p.as_path()) {
-                        let handler_path = p.clone();
-                        let handlers = handlers_clone.clone();
-                        let debounce_period = debounce_period.clone();
-
-                        let join_handle = tokio::spawn(async move {
-                            sleep(debounce_period).await;
-                            if let Some(handler) = handlers.lock().unwrap().get_mut(&handler_path) {
-                                handler.handle(handler_path);
-                            }
-                        });
-
-                        // abort the existing handle for debouncing
-                        if let Some(old_handle) = debouncers.insert(p.clone(), join_handle) {
-                            old_handle.abort();
+                // the innermost watched path handles the event, e.g. a worktree nested in another
+                let handler_path = handlers_clone
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .filter(|p| event_path.starts_with(p.as_path()))
+                    .max_by_key(|p| p.as_os_str().len())
+                    .cloned();
+                if let Some(p) = handler_path {
+                    let paths = &mut *changes.lock().unwrap();
+                    let paths = paths.entry(p.clone()).or_default();
+                    if !paths.contains(&event_path) {
+                        paths.push(event_path);
+                    }
+
+                    let handler_path = p.clone();
+                    let handlers = handlers_clone.clone();
+                    let changes = changes.clone();
+
+                    let join_handle = tokio::spawn(async move {
+                        sleep(debounce_period).await;
+                        let changed = changes
+                            .lock()
+                            .unwrap()
+                            .remove(&handler_path)
+                            .unwrap_or_default();
+                        if let Some(handler) = handlers.lock().unwrap().get_mut(&handler_path) {
+                            handler.handle_changes(handler_path, changed);
                         }
-                        break;
+                    });
+
+                    // abort the existing handle for debouncing
+                    if let Some(old_handle) = debouncers.insert(p, join_handle) {
+                        old_handle.abort();
                     }
                 }
             }
         });
 
-        let notify_watcher = Self::notify_watcher(&mode, handler)?;
+        let notify_watcher = Self::notify_watcher(mode, handler)?;
 
         Ok(Self {
             notify_watcher,
@@ -140,7 +162,7 @@ impl Watcher {
 #[cfg(test)]
 mod tests {
     use tempfile::{tempdir, NamedTempFile};
-    use tokio::sync::mpsc::UnboundedReceiver;
+    use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
     use super::*;
 
@@ -206,6 +228,38 @@ mod tests {
         assert_eq!(item.unwrap(), root_path);
     }
 
+    struct ChangesHandler(UnboundedSender<Vec<PathBuf>>);
+
+    impl Handler for ChangesHandler {
+        fn handle(&mut self, _path: PathBuf) {}
+
+        fn handle_changes(&mut self, _path: PathBuf, changed: Vec<PathBuf>) {
+            let _ = self.0.send(changed);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn debounce_changes() {
+        let root = tempdir().unwrap();
+        let root_path = canonicalize(root.path()).unwrap();
+        let mut watcher = Watcher::new(&WatchMode::Event, Duration::from_millis(100)).unwrap();
+        let (tx, mut rx) = unbounded_channel();
+        watcher
+            .watch_path(root.path(), Box::new(ChangesHandler(tx)))
+            .unwrap();
+
+        std::fs::write(root_path.join("a"), "a").unwrap();
+        sleep(Duration::from_millis(20)).await;
+        std::fs::write(root_path.join("b"), "b").unwrap();
+
+        let changed = rx.recv().await.unwrap();
+        assert!(changed.contains(&root_path.join("a")));
+        assert!(changed.contains(&root_path.join("b")));
+        // no second batch once the debounce period has passed again
+        sleep(Duration::from_millis(300)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn unwatch() {
         let root = tempdir().unwrap();